@@ -1,7 +1,9 @@
 use std::env::args;
-use std::fs::write;
+use std::fs::{read_to_string, write};
+use std::io::{stderr, IsTerminal};
 use std::path::{Path, PathBuf};
-use preproc::{FilesystemFetcher, generate_dependencies, build_file, CommentParser, create_depfile};
+use std::process::ExitCode;
+use preproc::{FilesystemFetcher, generate_dependencies, build, CommentParser, BlockCommentParser, LanguagePresets, SelectParser, create_depfile, Symbols, is_identifier, Options, IncludePolicy, CycleMode, PreprocessError, LineMarkers, SourceMap, diagnostics};
 use normpath::PathExt;
 
 enum NextIs {
@@ -9,11 +11,136 @@ enum NextIs {
     Comment,
     IncludePath,
     MakeOutput,
+    Define,
+    Undefine,
+    Sigil,
+    Alias,
+    BlockComment,
+    SourceMapFile,
 }
 
-fn main() {
+/// Applies `-DNAME`, `-DNAME=value` (define) or `-UNAME` (undefine) to the symbol table
+fn apply_define(symbols: &mut Symbols, arg: &str, define: bool) -> Result<(), String> {
+    let (name, value) = arg.split_once('=').unwrap_or((arg, "1"));
+    if name.is_empty() {
+        return Err(format!("missing symbol name in `{}`", arg));
+    } else if !is_identifier(name) {
+        return Err(format!("invalid symbol name in `{}`", arg));
+    }
+
+    if define {
+        symbols.define(name, value);
+    } else if arg.contains('=') {
+        return Err(format!("can't assign a value when undefining `{}`", arg));
+    } else {
+        symbols.undefine(name);
+    }
+    Ok(())
+}
+
+/// Parses `-a<alias>=<keyword>`
+fn parse_alias(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((alias, keyword)) if !alias.is_empty() && !keyword.is_empty() => Ok((alias.to_owned(), keyword.to_owned())),
+        _ => Err(format!("expected an alias as `<alias>=<keyword>`, found `{}`", arg)),
+    }
+}
+
+enum SourceMapFormat {
+    Json,
+    V3,
+}
+
+enum ErrorFormat {
+    Human,
+    Json,
+}
+
+fn report(error: &PreprocessError, format: &ErrorFormat, color: bool) {
+    match format {
+        ErrorFormat::Human => eprint!("{}", diagnostics::render(error, color)),
+        ErrorFormat::Json => eprintln!("{}", diagnostics::render_json(error)),
+    }
+}
+
+fn report_warning(warning: &PreprocessError, format: &ErrorFormat, color: bool) {
+    match format {
+        ErrorFormat::Human => eprint!("{}", diagnostics::render_warning(warning, color)),
+        ErrorFormat::Json => eprintln!("{}", diagnostics::render_json_warning(warning)),
+    }
+}
+
+/// `preprocess locate <output>:<line> [--source-map <path>]`, prints where a line of a built file
+/// came from. The map defaults to `<output>.map` and must be in the json format. Fails for
+/// positions without an original location, including line markers.
+fn locate(mut args: impl Iterator<Item = String>) -> ExitCode {
+    let mut position = None;
+    let mut map_file = None;
+
+    while let Some(arg) = args.next() {
+        if arg == "--source-map" {
+            map_file = args.next().map(PathBuf::from);
+            if map_file.is_none() {
+                println!("unfinished argument: source map file not specified");
+                return ExitCode::FAILURE;
+            }
+        } else if let Some(path) = arg.strip_prefix("--source-map=") {
+            map_file = Some(PathBuf::from(path));
+        } else if position.is_none() {
+            position = Some(arg);
+        } else {
+            println!("invalid argument {}", arg);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let Some((output, line)) = position.as_deref().and_then(|p| p.rsplit_once(':')) else {
+        println!("please supply a position as `<output>:<line>`");
+        return ExitCode::FAILURE;
+    };
+    let line = match line.parse::<usize>() {
+        Ok(line) if line > 0 => line,
+        _ => {
+            println!("invalid line number {}", line);
+            return ExitCode::FAILURE;
+        }
+    };
+    let map_file = map_file.unwrap_or_else(|| PathBuf::from(format!("{}.map", output)));
+
+    let map = match read_to_string(&map_file).map_err(|e| e.to_string()).and_then(|text| SourceMap::from_json(&text)) {
+        Ok(map) => map,
+        Err(e) => {
+            println!("failed to read source map {}: {}", map_file.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match map.locate(line) {
+        Some(origin) => {
+            println!("{}", origin.location);
+            for location in origin.include_stack.iter().rev() {
+                println!("    included from {}", location);
+            }
+            ExitCode::SUCCESS
+        }
+        None if line <= map.lines.len() => {
+            println!("{}:{} was generated by the preprocessor", output, line);
+            ExitCode::FAILURE
+        }
+        None => {
+            println!("{} has only {} lines", output, map.lines.len());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn main() -> ExitCode {
     use NextIs::*;
 
+    if args().nth(1).as_deref() == Some("locate") {
+        return locate(args().skip(2));
+    }
+
     let mut fetcher = FilesystemFetcher::new();
     let mut file = None;
     let mut output_file = None;
@@ -22,6 +149,14 @@ fn main() {
     let mut makefile = false;
     let mut makeoutput = None;
     let mut verbose = false;
+    let mut error_format = ErrorFormat::Human;
+    let mut color = stderr().is_terminal();
+    let mut source_map = None;
+    let mut source_map_format = SourceMapFormat::Json;
+    let mut options = Options::default();
+    let mut sigil = None;
+    let mut aliases = Vec::new();
+    let mut block_comment = None;
 
 
     for arg in args().skip(1) {
@@ -32,7 +167,7 @@ fn main() {
                         output_file = Some(PathBuf::from(arg));
                     } else {
                         println!("can't specify multiple output-files");
-                        return;
+                        return ExitCode::FAILURE;
                     }
                 }
                 Comment => {
@@ -40,7 +175,7 @@ fn main() {
                         comment = Some(arg); 
                     } else {
                         println!("can't specify multiple output-files");
-                        return;
+                        return ExitCode::FAILURE;
                     }
                 }
                 IncludePath => {
@@ -50,7 +185,45 @@ fn main() {
                     if makeoutput.is_none() {
                         makeoutput = Some(PathBuf::from(arg))
                     } else {
-                        println!("can't specify multiple dependency file outputs")
+                        println!("can't specify multiple dependency file outputs");
+                        return ExitCode::FAILURE;
+                    }
+                }
+                Define | Undefine => {
+                    if let Err(e) = apply_define(&mut options.symbols, &arg, matches!(n, Define)) {
+                        println!("{}", e);
+                        return ExitCode::FAILURE;
+                    }
+                }
+                Sigil => {
+                    if sigil.is_none() {
+                        sigil = Some(arg);
+                    } else {
+                        println!("sigil can't be specified twice");
+                        return ExitCode::FAILURE;
+                    }
+                }
+                SourceMapFile => {
+                    if source_map.is_none() {
+                        source_map = Some(PathBuf::from(arg));
+                    } else {
+                        println!("can't specify multiple source maps");
+                        return ExitCode::FAILURE;
+                    }
+                }
+                BlockComment => {
+                    if block_comment.is_none() {
+                        block_comment = Some(arg);
+                    } else {
+                        println!("block comment can't be specified twice");
+                        return ExitCode::FAILURE;
+                    }
+                }
+                Alias => match parse_alias(&arg) {
+                    Ok(alias) => aliases.push(alias),
+                    Err(e) => {
+                        println!("{}", e);
+                        return ExitCode::FAILURE;
                     }
                 }
             }
@@ -59,7 +232,65 @@ fn main() {
         }
 
         let arg_str = arg.as_str();
-        if let Some(option) = arg_str.strip_prefix("-") {
+        if let Some(long_option) = arg_str.strip_prefix("--") {
+            if long_option == "every-include" {
+                options.policy = IncludePolicy::EveryInclude;
+            } else if long_option == "indent-includes" {
+                options.indent_includes = true;
+            } else if long_option == "strict-cycles" {
+                options.cycles = CycleMode::Strict;
+            } else if let Some(format) = long_option.strip_prefix("error-format=") {
+                error_format = match format {
+                    "human" => ErrorFormat::Human,
+                    "json" => ErrorFormat::Json,
+                    _ => {
+                        println!("unknown error format {}, expected `human` or `json`", format);
+                        return ExitCode::FAILURE;
+                    }
+                };
+            } else if let Some(markers) = long_option.strip_prefix("line-markers=") {
+                options.line_markers = match markers {
+                    "none" => LineMarkers::None,
+                    "c" => LineMarkers::C,
+                    "comment" => LineMarkers::Comment,
+                    _ => {
+                        println!("unknown line marker style {}, expected `none`, `c` or `comment`", markers);
+                        return ExitCode::FAILURE;
+                    }
+                };
+            } else if long_option == "source-map" {
+                next_is = Some(SourceMapFile);
+            } else if let Some(path) = long_option.strip_prefix("source-map=") {
+                if source_map.is_none() {
+                    source_map = Some(PathBuf::from(path));
+                } else {
+                    println!("can't specify multiple source maps");
+                    return ExitCode::FAILURE;
+                }
+            } else if let Some(format) = long_option.strip_prefix("source-map-format=") {
+                source_map_format = match format {
+                    "json" => SourceMapFormat::Json,
+                    "v3" => SourceMapFormat::V3,
+                    _ => {
+                        println!("unknown source map format {}, expected `json` or `v3`", format);
+                        return ExitCode::FAILURE;
+                    }
+                };
+            } else if let Some(when) = long_option.strip_prefix("color=") {
+                color = match when {
+                    "always" => true,
+                    "never" => false,
+                    "auto" => stderr().is_terminal(),
+                    _ => {
+                        println!("unknown color choice {}, expected `auto`, `always` or `never`", when);
+                        return ExitCode::FAILURE;
+                    }
+                };
+            } else {
+                println!("unknown option --{}", long_option);
+                return ExitCode::FAILURE;
+            }
+        } else if let Some(option) = arg_str.strip_prefix("-") {
             if let Some(new_dir) = option.strip_prefix("I") {
                 if new_dir.is_empty() {
                     next_is = Some(IncludePath);
@@ -74,7 +305,51 @@ fn main() {
                         comment = Some(comment_str.to_owned());
                     } else {
                         println!("comment can't be specified twice");
-                        return;
+                        return ExitCode::FAILURE;
+                    }
+                }
+            } else if let Some(define) = option.strip_prefix("D") {
+                if define.is_empty() {
+                    next_is = Some(Define);
+                } else if let Err(e) = apply_define(&mut options.symbols, define, true) {
+                    println!("{}", e);
+                    return ExitCode::FAILURE;
+                }
+            } else if let Some(undefine) = option.strip_prefix("U") {
+                if undefine.is_empty() {
+                    next_is = Some(Undefine);
+                } else if let Err(e) = apply_define(&mut options.symbols, undefine, false) {
+                    println!("{}", e);
+                    return ExitCode::FAILURE;
+                }
+            } else if let Some(block_str) = option.strip_prefix("b") {
+                if block_str.is_empty() {
+                    next_is = Some(BlockComment);
+                } else if block_comment.is_none() {
+                    block_comment = Some(block_str.to_owned());
+                } else {
+                    println!("block comment can't be specified twice");
+                    return ExitCode::FAILURE;
+                }
+            } else if let Some(sigil_str) = option.strip_prefix("s") {
+                if sigil_str.is_empty() {
+                    next_is = Some(Sigil);
+                } else if sigil.is_none() {
+                    sigil = Some(sigil_str.to_owned());
+                } else {
+                    println!("sigil can't be specified twice");
+                    return ExitCode::FAILURE;
+                }
+            } else if let Some(alias) = option.strip_prefix("a") {
+                if alias.is_empty() {
+                    next_is = Some(Alias);
+                } else {
+                    match parse_alias(alias) {
+                        Ok(alias) => aliases.push(alias),
+                        Err(e) => {
+                            println!("{}", e);
+                            return ExitCode::FAILURE;
+                        }
                     }
                 }
             } else if option == "o" {
@@ -85,19 +360,19 @@ fn main() {
                     next_is = Some(MakeOutput);
                 } else if make_opt != "D" {
                     println!("unknown option -M{}", make_opt);
-                    return;
+                    return ExitCode::FAILURE;
                 }
             } else if option == "v" {
                 verbose = true;
             } else {
                 println!("unknown option -{}", option);
-                return;
+                return ExitCode::FAILURE;
             }
         } else if file.is_none() {
             file = Some(arg); 
         } else {
             println!("invalid argument {}", arg);
-            return;
+            return ExitCode::FAILURE;
         }
     }
 
@@ -108,8 +383,14 @@ fn main() {
             Comment => println!("comment not supplied"),
             IncludePath => println!("include path not specified"),
             MakeOutput => println!("dependency file not specified"),
+            Define => println!("symbol to define not specified"),
+            Undefine => println!("symbol to undefine not specified"),
+            Sigil => println!("sigil not specified"),
+            Alias => println!("alias not specified"),
+            BlockComment => println!("block comment delimiters not specified"),
+            SourceMapFile => println!("source map file not specified"),
         }
-        return;
+        return ExitCode::FAILURE;
     }
 
     let root = Path::new("./").normalize().map_err(|e| {println!("error while normalizing path to output-file: {e}"); e}).ok();
@@ -119,7 +400,7 @@ fn main() {
         f
     } else {
         println!("please supply a file");
-        return;
+        return ExitCode::FAILURE;
     };
 
     let output_file = match output_file {
@@ -158,16 +439,58 @@ fn main() {
 
     
 
-    let comment: CommentParser = comment.unwrap_or(String::from("//")).into();
+    let sigil = sigil.as_deref().unwrap_or("&");
+    // an explicit comment applies to every file, otherwise it's chosen by file extension
+    let parser: Box<dyn SelectParser> = match (comment, block_comment) {
+        (Some(_), Some(_)) => {
+            println!("can't use both a line comment and a block comment");
+            return ExitCode::FAILURE;
+        }
+        (None, None) => Box::new(LanguagePresets::builtin(sigil, &aliases)),
+        (Some(comment), None) => {
+            let mut parser = CommentParser::with_sigil(&comment, sigil);
+            for (alias, keyword) in &aliases {
+                parser.add_alias(alias, keyword);
+            }
+            Box::new(parser)
+        }
+        (None, Some(block)) => {
+            let Some((open, close)) = block.split_once(char::is_whitespace) else {
+                println!("block comment must be given as `<open> <close>`, e.g. \"<!-- -->\"");
+                return ExitCode::FAILURE;
+            };
+            let mut parser = BlockCommentParser::with_sigil(open, close.trim(), sigil);
+            for (alias, keyword) in &aliases {
+                parser.add_alias(alias, keyword);
+            }
+            Box::new(parser)
+        }
+    };
 
-    match generate_dependencies(&file, &mut fetcher, &comment) {
-        Ok((_, deps)) => match build_file(&deps) {
-            Ok(new_source) => match write(&output_file, new_source) {
+    match generate_dependencies(&file, &mut fetcher, parser.as_ref(), &options) {
+        Ok((_, deps)) => match build(&deps, &options) {
+            Ok(output) => match write(&output_file, output.source) {
                 Ok(_) => {
+                    let mut status = ExitCode::SUCCESS;
+                    let map = output.map;
+                    for warning in &output.warnings {
+                        report_warning(warning, &error_format, color);
+                    }
+                    if let Some(map_file) = &source_map {
+                        let map_source = match source_map_format {
+                            SourceMapFormat::Json => map.to_json(),
+                            SourceMapFormat::V3 => map.to_source_map_v3(&out_file_rep),
+                        };
+                        if let Err(e) = write(map_file, map_source) {
+                            println!("failed to write file: {:?}", e);
+                            status = ExitCode::FAILURE;
+                        }
+                    }
                     if makefile {        
                         let makesource = create_depfile(&out_file_rep, root_repr, &deps);
                         if let Err(e) = write(makeoutput, makesource) {
                             println!("failed to write file: {:?}", e);
+                            status = ExitCode::FAILURE;
                         }
                     }
                     if verbose {
@@ -182,17 +505,21 @@ fn main() {
                         }
                         println!("wrote to {}", out_file_rep);
                     }
+                    status
                 }
                 Err(e) => {
-                    println!("failed to write file: {:?}", e)
+                    println!("failed to write file: {:?}", e);
+                    ExitCode::FAILURE
                 }
             }
             Err(e) => {
-                println!("error while building file: {}", e)
+                report(&e, &error_format, color);
+                ExitCode::FAILURE
             }
         }
         Err(e) => {
-            println!("error while generating/processing dependencies: {}", e)
+            report(&e, &error_format, color);
+            ExitCode::FAILURE
         }
     }
 }
@@ -1,88 +1,464 @@
-use std::collections::HashMap;
-use crate::{process::{ParseLine, Source, IncludePoint}, filefetcher::{FileName, FetchedFile}, FileFetcher};
+use std::collections::{HashMap, HashSet};
+use std::ops::{Index, Range};
+use crate::{presets::SelectParser, process::{Source, PreprocCommand, Conditionals, Selection, apply_definition, expand_arguments}, filefetcher::{FileName, FetchedFile, FetchedBytes, is_glob}, FileFetcher, Symbols, Options, IncludePolicy, CycleMode, error::{PreprocessError, ErrorKind, Location}};
 
 #[derive(Debug)]
 pub struct InsertionPoint {
     pub index: usize,
     pub fname: String,
+    /// the part of the file which is included, `None` for all of it
+    pub selection: Option<Selection>,
+    /// the arguments of the include, as written in the directive
+    pub args: Vec<(String, String)>,
 }
 
 impl InsertionPoint {
     pub fn new(index: usize, fname: String) -> InsertionPoint {
-        InsertionPoint { index, fname }
+        InsertionPoint { index, fname, selection: None, args: Vec::new() }
     }
 }
+
+/// How a file is included: the part of it and the arguments bound while it's expanded
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Variant<'a> {
+    pub selection: Option<&'a Selection>,
+    pub args: &'a [(String, String)],
+}
 #[derive(Debug)]
 pub struct FileData {
     pub source: String,
     pub points: Vec<InsertionPoint>,
+    pub directives: Vec<(usize, PreprocCommand)>,
+    /// comment delimiters of the file's language, if its parser tells them
+    pub comment: Option<(String, String)>,
+    /// whether `directives` were parsed, files only included raw are never parsed
+    pub parsed: bool,
+}
+
+impl FileData {
+    /// The indices of the lines `selection` picks out of the file, all lines for `None`
+    pub fn selected_lines(&self, selection: Option<&Selection>) -> Result<Range<usize>, String> {
+        let line_count = self.source.lines().count();
+        match selection {
+            Some(selection) => selection.range(&self.directives, line_count),
+            None => Ok(0..line_count),
+        }
+    }
+}
+
+/// The files of a dependency tree, kept in the order they were discovered, together with the
+/// seed files the tree was generated from
+#[derive(Debug, Default)]
+pub struct Dependencies {
+    files: HashMap<String, FileData>,
+    order: Vec<String>,
+    roots: Vec<String>,
+    /// directories where missing optional includes would appear
+    watched: Vec<String>,
+    /// files pasted by `&embed`, which are kept as bytes
+    embedded: Vec<(String, Vec<u8>)>,
+    /// paths on disk of the files and directories whose name isn't one, `None` for those which
+    /// aren't on disk and are left out of dependency files
+    disk_paths: HashMap<String, Option<String>>,
+}
+
+impl Dependencies {
+    pub fn new() -> Dependencies {
+        Dependencies::default()
+    }
+
+    /// Inserts or replaces `name`, a replaced file keeps its position
+    pub fn insert(&mut self, name: String, data: FileData) {
+        if self.files.insert(name.clone(), data).is_none() {
+            self.order.push(name);
+        }
+    }
+
+    /// Marks `name` as a file which is built from, roots are built in the order they were added
+    pub fn add_root(&mut self, name: String) {
+        if !self.roots.contains(&name) {
+            self.roots.push(name);
+        }
+    }
+
+    pub fn roots(&self) -> &[String] {
+        &self.roots
+    }
+
+    /// Records a directory whose contents affect the output, e.g. where a missing optional
+    /// include would be found
+    pub fn add_watched(&mut self, dir: String) {
+        if !self.watched.contains(&dir) {
+            self.watched.push(dir);
+        }
+    }
+
+    pub fn watched(&self) -> &[String] {
+        &self.watched
+    }
+
+    /// Adds the bytes of a file pasted by `&embed`, unless already present
+    pub fn add_embedded(&mut self, name: String, content: Vec<u8>) {
+        if self.embedded(&name).is_none() {
+            self.embedded.push((name, content));
+        }
+    }
+
+    /// Records the path on disk of the file or directory `name`, see [`FileFetcher::disk_path`]
+    pub fn add_disk_path(&mut self, name: String, path: Option<String>) {
+        if path.as_ref() != Some(&name) {
+            self.disk_paths.insert(name, path);
+        }
+    }
+
+    /// The path on disk of the file or directory `name`, if it's on disk
+    pub fn disk_path<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        match self.disk_paths.get(name) {
+            Some(path) => path.as_deref(),
+            None => Some(name),
+        }
+    }
+
+    pub fn embedded(&self, name: &str) -> Option<&[u8]> {
+        self.embedded.iter().find(|(n, _)| n == name).map(|(_, content)| content.as_slice())
+    }
+
+    /// Names of the embedded files in discovery order
+    pub fn embedded_keys(&self) -> impl Iterator<Item = &String> {
+        self.embedded.iter().map(|(name, _)| name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FileData> {
+        self.files.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut FileData> {
+        self.files.get_mut(name)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.files.contains_key(name)
+    }
+
+    /// Names of all files in discovery order
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.order.iter()
+    }
+
+    /// All files in discovery order
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &FileData)> {
+        self.order.iter().map(|name| (name, &self.files[name]))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &FileData> {
+        self.iter().map(|(_, data)| data)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
 }
 
-pub type Dependencies = HashMap<String, FileData>;
+impl Index<&str> for Dependencies {
+    type Output = FileData;
 
-pub fn generate_dependencies<F, P>(seed: &str, fetcher: &mut F, parser: &P) -> Result<(String, Dependencies), String> 
+    fn index(&self, name: &str) -> &FileData {
+        &self.files[name]
+    }
+}
+
+/// Walks the seed file and everything it includes, starting out with the symbols of `options`
+/// defined. Each file is parsed with the parser `parser` selects for its resolved name.
+/// Includes inside conditional blocks that are excluded are not followed.
+///
+/// Definitions are visible from the line after them onwards, including in files included
+/// later on and in the including file after the insertion point.
+pub fn generate_dependencies<F, P>(seed: &str, fetcher: &mut F, parser: &P, options: &Options) -> Result<(String, Dependencies), PreprocessError> 
 where
     F: FileFetcher,
-    P: ParseLine,
+    P: SelectParser + ?Sized,
 {
     let start = FileName::LocalTo(seed.to_owned(), "./".to_owned());
-    let fname = fetcher.resolve_name(&start).ok_or(format!("file not found {}", start))?;
     let mut deptree = Dependencies::new();
-    build_deptree(start.clone(), &mut deptree, fetcher, parser)?;
+    let mut symbols = options.symbols.clone();
+    let mut inclusions = Inclusions::new(options);
+    let fname = load_file(&start, &mut deptree, fetcher, parser, &inclusions, false)?;
+    deptree.add_root(fname.clone());
+    build_deptree(&fname, Variant::default(), &mut deptree, fetcher, parser, &mut symbols, &mut inclusions)?;
     Ok((fname, deptree))
 }
 
-fn build_deptree<F, P>(fname: FileName, deptree: &mut Dependencies, fetcher: &mut F, parser: &P) -> Result<(), String> 
+/// Fetches `fname` into `deptree` unless already present, returning the resolved name. The file
+/// is parsed unless it's only loaded `raw`.
+fn load_file<F, P>(fname: &FileName, deptree: &mut Dependencies, fetcher: &mut F, parser: &P, inclusions: &Inclusions, raw: bool) -> Result<String, PreprocessError>
 where
     F: FileFetcher,
-    P: ParseLine,
+    P: SelectParser + ?Sized,
 {
-    // resolve name via fetcher
-    let FetchedFile { name, content } = fetcher.fetch(&fname).ok_or(format!("file not found {}", fname))?;
-    let mut fdata = FileData { source: content, points: Vec::new() };
-    let source = Source::from_str(&fdata.source);
-    
-    // add this file to deptree, with placeholder file-data
-    deptree.insert(name.clone(), FileData { source: String::new(), points: Vec::new()});
-
-    // Process source and parse include points into insertion points
-    for include_point in source.process(parser)?.get_include_points() {
-        // parse type of include and point of insertion
-        let (i, subname) = match include_point {
-            IncludePoint::Global(i, f) => (i, FileName::Global(f.to_owned())),
-            IncludePoint::Local(i, f) => (i, FileName::LocalTo(f.to_owned(), name.clone()))
-        };
+    let resolved = fetcher.resolve_name(fname);
+    let name = match resolved.clone().filter(|n| deptree.contains_key(n)) {
+        Some(name) => name,
+        None => {
+            let FetchedFile { name, content } = fetcher.fetch(fname).ok_or_else(|| {
+                let problem = if resolved.is_some() { "file could not be read as text" } else { "file not found" };
+                // located at the include being expanded, the seed has none
+                inclusions.locate_include(ErrorKind::Fetch, format!("{} {}", problem, fname), deptree)
+            })?;
+            let comment = parser.parser_for(&name).comment_delimiters().map(|(open, close)| (open.to_owned(), close.to_owned()));
+            deptree.add_disk_path(name.clone(), fetcher.disk_path(&name));
+            deptree.insert(name.clone(), FileData { source: content, points: Vec::new(), directives: Vec::new(), comment, parsed: false });
+            name
+        }
+    };
 
-        // get resolved name
-        let rname = fetcher.resolve_name(&subname).ok_or(format!("file not found {}", subname))?;
+    // a file only included raw so far is parsed once it's included normally
+    let data = deptree.get_mut(&name).unwrap();
+    if !raw && !data.parsed {
+        data.directives = Source::from_str(&data.source)
+            .process(parser.parser_for(&name))
+            .map_err(|e| e.in_file(&name).included_from(inclusions.include_stack()))?
+            .into_commands();
+        data.parsed = true;
+    }
+    Ok(name)
+}
+
+/// Walks the directives of the `variant` of the already loaded file `name`, loading and walking
+/// the files it includes
+fn build_deptree<F, P>(name: &str, variant: Variant, deptree: &mut Dependencies, fetcher: &mut F, parser: &P, symbols: &mut Symbols, inclusions: &mut Inclusions) -> Result<(), PreprocessError> 
+where
+    F: FileFetcher,
+    P: SelectParser + ?Sized,
+{
+    let lines = deptree[name].selected_lines(variant.selection).map_err(|e| inclusions.locate_include(ErrorKind::Fetch, e, deptree))?;
+    let directives: Vec<_> = deptree[name].directives.iter().filter(|(i, _)| lines.contains(i)).cloned().collect();
+    inclusions.enter(name, variant);
 
-        // add to insertion-points if not yet present in file.
-        if fdata.points.iter().all(|InsertionPoint{index: _, fname}| fname != &rname)
+    // Evaluate conditionals and definitions, and parse the active include points into insertion points
+    let mut conditionals = Conditionals::at_line(&deptree[name].directives, lines.start, symbols)
+        .map_err(|(i, e)| inclusions.locate(ErrorKind::Parse, e, deptree, i))?;
+    for (i, command) in &directives {
+        if conditionals.apply(command, symbols).map_err(|e| inclusions.locate(ErrorKind::Parse, e, deptree, *i))?
+            || !conditionals.is_active()
+            || apply_definition(command, symbols)
         {
-            // also subprocess this tree if not yet done
-            if !deptree.contains_key(&rname) {
-                build_deptree(subname, deptree, fetcher, parser)?;
+            continue;
+        }
+
+        // parse type of include
+        let (subname, options) = match command {
+            PreprocCommand::Include(f, options) => (FileName::Global(f.clone()), options),
+            PreprocCommand::IncludeLocal(f, options) => (FileName::LocalTo(f.clone(), name.to_owned()), options),
+            PreprocCommand::Once => {
+                inclusions.mark_once(name);
+                continue;
+            }
+            PreprocCommand::Error(message) => {
+                return Err(inclusions.locate(ErrorKind::Directive, directive_message(message, "&error", symbols), deptree, *i));
+            }
+            _ => continue,
+        };
+
+        // a glob becomes one include per match, its directory is watched for new matches. An
+        // existing file whose name looks like a glob, e.g. `a[1].txt`, is included as it is.
+        let subnames = match &subname {
+            FileName::Global(f) | FileName::LocalTo(f, _) if is_glob(f) && fetcher.resolve_name(&subname).is_none() => {
+                watch_search_dirs(&subname, deptree, fetcher);
+                let matches = fetcher.glob(&subname);
+                if matches.is_empty() && !options.optional {
+                    return Err(inclusions.locate(ErrorKind::Fetch, format!("no files match {}", subname), deptree, *i));
+                }
+                matches
+            }
+            _ => vec![subname],
+        };
+
+        for subname in subnames {
+            // get resolved name and add to insertion-points, a missing optional include is skipped
+            let Some(rname) = fetcher.resolve_name(&subname) else {
+                if options.optional {
+                    watch_search_dirs(&subname, deptree, fetcher);
+                    continue;
+                }
+                return Err(inclusions.locate(ErrorKind::Fetch, format!("file not found {}", subname), deptree, *i));
+            };
+            let points = &mut deptree.get_mut(name).unwrap().points;
+            if !points.iter().any(|p| p.index == *i && p.fname == rname) {
+                points.push(InsertionPoint { selection: options.selection.clone(), args: options.args.clone(), ..InsertionPoint::new(*i, rname.clone()) });
+            }
+
+            inclusions.include_at(*i);
+
+            // embedded files are only read as bytes
+            if options.embed.is_some() {
+                if deptree.embedded(&rname).is_none() {
+                    let FetchedBytes { name, content } = fetcher.fetch_bytes(&subname)
+                        .ok_or_else(|| inclusions.locate(ErrorKind::Fetch, format!("file could not be read {}", subname), deptree, *i))?;
+                    deptree.add_disk_path(name.clone(), fetcher.disk_path(&name));
+                    deptree.add_embedded(name, content);
+                }
+                continue;
+            }
+
+            // raw files are pasted as they are, without following their includes
+            if options.raw {
+                load_file(&subname, deptree, fetcher, parser, inclusions, true)?;
+                deptree[&rname].selected_lines(options.selection.as_ref()).map_err(|e| inclusions.locate(ErrorKind::Fetch, e, deptree, *i))?;
+                continue;
+            }
+
+            let args = expand_arguments(&options.args, symbols);
+            let variant = Variant { selection: options.selection.as_ref(), args: &args };
+            if let Some(chain) = inclusions.cycle(&rname, variant.selection) {
+                // in lenient mode the build warns about the cycle
+                if inclusions.cycles == CycleMode::Strict {
+                    return Err(inclusions.locate(ErrorKind::Cycle, format!("include cycle {}", chain), deptree, *i));
+                }
+            }
+
+            // also subprocess this tree if it's expanded here
+            if inclusions.should_include(&rname, variant) {
+                load_file(&subname, deptree, fetcher, parser, inclusions, false)?;
+                let previous = symbols.bind_arguments(&args);
+                build_deptree(&rname, variant, deptree, fetcher, parser, symbols, inclusions)?;
+                symbols.unbind_arguments(previous);
             }
-            fdata.points.push(InsertionPoint {index: i, fname: rname});
         }
     };
 
-    // update placeholder in deptree
-    deptree.insert(name, fdata);
-
+    inclusions.leave();
     Ok(())
 }
 
-/// Join two dependencytrees
+/// Watches the directories `name` is looked up in
+fn watch_search_dirs<F: FileFetcher>(name: &FileName, deptree: &mut Dependencies, fetcher: &mut F) {
+    for dir in fetcher.search_dirs(name) {
+        deptree.add_disk_path(dir.clone(), fetcher.disk_path(&dir));
+        deptree.add_watched(dir);
+    }
+}
+
+/// The message of an `&error` or `&warning` directive with symbols substituted
+pub(crate) fn directive_message(message: &str, directive: &str, symbols: &Symbols) -> String {
+    if message.is_empty() {
+        format!("`{}` directive", directive)
+    } else {
+        symbols.expand(message).into_owned()
+    }
+}
+
+/// The name, selection and arguments an expanded file is told apart by
+type VariantKey = (String, Option<Selection>, Vec<(String, String)>);
+
+/// Decides which includes are expanded. Shared by the dependency walk and the build so that
+/// both expand the same files in the same order.
+///
+/// A file is told apart by its name, the selection and the arguments it's included with, so
+/// different regions of a file, or a template with different arguments, may each be included
+/// once. Cycles are detected by name and selection alone.
+pub(crate) struct Inclusions {
+    policy: IncludePolicy,
+    pub cycles: CycleMode,
+    visited: HashSet<VariantKey>,
+    /// files which contained an active `&once`
+    once: HashSet<String>,
+    /// files currently being expanded, with the line of the include being expanded in each
+    stack: Vec<(String, Option<Selection>, usize)>,
+}
+
+impl Inclusions {
+    pub fn new(options: &Options) -> Inclusions {
+        Inclusions { policy: options.policy, cycles: options.cycles, visited: HashSet::new(), once: HashSet::new(), stack: Vec::new() }
+    }
+
+    pub fn should_include(&self, name: &str, variant: Variant) -> bool {
+        // `&once` holds for every selection and argument set of the file
+        let repeated = self.once.contains(name) || match self.policy {
+            IncludePolicy::OncePerOutput => self.visited.contains(&(name.to_owned(), variant.selection.cloned(), variant.args.to_vec())),
+            IncludePolicy::EveryInclude => false,
+        };
+        !repeated && self.cycle(name, variant.selection).is_none()
+    }
+
+    /// Records that the innermost file is at the include on `line`
+    pub fn include_at(&mut self, line: usize) {
+        if let Some((_, _, l)) = self.stack.last_mut() {
+            *l = line;
+        }
+    }
+
+    /// The includes currently being expanded, outermost first
+    pub fn include_stack(&self) -> Vec<Location> {
+        self.stack.iter().map(|(s, _, l)| Location::new(s, l + 1)).collect()
+    }
+
+    /// Creates an error on `line` of the innermost file, `deptree` providing the text of the line
+    pub fn locate(&self, kind: ErrorKind, message: impl Into<String>, deptree: &Dependencies, line: usize) -> PreprocessError {
+        let mut include_stack = self.include_stack();
+        let error = PreprocessError::new(kind, message);
+        match include_stack.pop() {
+            Some(Location { file, .. }) => {
+                let text = deptree.get(&file).and_then(|d| d.source.lines().nth(line)).unwrap_or_default();
+                error.in_file(&file).at_line(line + 1, text).included_from(include_stack)
+            }
+            None => error,
+        }
+    }
+
+    /// Creates an error on the include being expanded in the innermost file
+    pub fn locate_include(&self, kind: ErrorKind, message: impl Into<String>, deptree: &Dependencies) -> PreprocessError {
+        let line = self.stack.last().map_or(0, |(_, _, l)| *l);
+        self.locate(kind, message, deptree, line)
+    }
+
+    /// If including `name` closes a cycle, describes it as `a.txt:2 -> b.txt:5 -> a.txt`,
+    /// with 1-based line numbers
+    pub fn cycle(&self, name: &str, selection: Option<&Selection>) -> Option<String> {
+        let start = self.stack.iter().position(|(s, sel, _)| s == name && sel.as_ref() == selection)?;
+        let chain: Vec<_> = self.stack[start..].iter().map(|(s, _, l)| format!("{}:{}", s, l + 1)).collect();
+        Some(format!("{} -> {}", chain.join(" -> "), name))
+    }
+
+    pub fn enter(&mut self, name: &str, variant: Variant) {
+        self.visited.insert((name.to_owned(), variant.selection.cloned(), variant.args.to_vec()));
+        self.stack.push((name.to_owned(), variant.selection.cloned(), 0));
+    }
+
+    pub fn leave(&mut self) {
+        self.stack.pop();
+    }
+
+    pub fn mark_once(&mut self, name: &str) {
+        self.once.insert(name.to_owned());
+    }
+}
+
+/// Join two dependencytrees, the files and roots of `dep2` are placed after those of `dep1`
 pub fn join_dependencies(mut dep1: Dependencies, dep2: Dependencies) -> Dependencies {
-    dep1.extend(dep2.into_iter());
+    let Dependencies { mut files, order, roots, watched, embedded, disk_paths } = dep2;
+    for name in order {
+        let data = files.remove(&name).unwrap();
+        dep1.insert(name, data);
+    }
+    roots.into_iter().for_each(|root| dep1.add_root(root));
+    watched.into_iter().for_each(|dir| dep1.add_watched(dir));
+    embedded.into_iter().for_each(|(name, content)| dep1.add_embedded(name, content));
+    dep1.disk_paths.extend(disk_paths);
     dep1
 }
 
-/// Creates the source for a dependency file: `<file>: [<dependency1> [<dependency2> ...]]`
+/// Creates the source for a dependency file: `<file>: [<dependency1> [<dependency2> ...]]`,
+/// listing the dependencies in discovery order followed by the embedded files and the watched
+/// directories, so that creating a missing optional include triggers a rebuild. Files and
+/// directories are listed by their path on disk, those which aren't on disk are left out.
 pub fn create_depfile(filename: &str, root: Option<&str>, points: &Dependencies) -> String {
 
-    let fnames: Vec<_> = points.keys().map(|k| match root {
+    let fnames: Vec<_> = points.keys().chain(points.embedded_keys()).chain(points.watched()).filter_map(|k| points.disk_path(k)).map(|k| match root {
+        Some(r) if k == r => ".".to_owned(),
         Some(r) => k.strip_prefix(r).or_else(|| {println!("failed to strip prefix"); None}).unwrap_or(k).to_owned(),
         None => k.to_owned()
     }).collect();
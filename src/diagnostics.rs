@@ -0,0 +1,120 @@
+use crate::error::PreprocessError;
+use crate::json;
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// 1-based column of the first non-whitespace character of `line`
+fn column(line: &str) -> usize {
+    line.chars().take_while(|c| c.is_whitespace()).count() + 1
+}
+
+/// Renders `error` for a terminal, showing the offending line with its directive underlined
+/// and the includes leading there:
+///
+/// ```text
+/// error: file not found <b.txt>
+///   --> test/a.txt:2:1
+///    |
+///  2 | //&include <b.txt>
+///    | ^^^^^^^^^^^^^^^^^^
+///    = included from test/d.txt:2
+/// ```
+pub fn render(error: &PreprocessError, color: bool) -> String {
+    render_as("error", RED, error, color)
+}
+
+/// Like [`render`], for a warning collected during a build
+pub fn render_warning(warning: &PreprocessError, color: bool) -> String {
+    render_as("warning", YELLOW, warning, color)
+}
+
+fn render_as(label: &str, style: &'static str, error: &PreprocessError, color: bool) -> String {
+    let paint = |style: &'static str| if color { style } else { "" };
+    let (accent, blue, bold, reset) = (paint(style), paint(BLUE), paint(BOLD), paint(RESET));
+
+    let mut out = format!("{accent}{label}{reset}{bold}: {}{reset}\n", error.message);
+    let gutter = " ".repeat(error.line.map_or(0, |l| l.to_string().len()));
+
+    if let Some(file) = &error.file {
+        match (error.line, &error.directive) {
+            (Some(line), Some(text)) => {
+                let col = column(text);
+                let width = text.trim().chars().count().max(1);
+                out += &format!("{gutter}{blue}-->{reset} {}:{}:{}\n", file, line, col);
+                out += &format!("{gutter} {blue}|{reset}\n");
+                out += &format!("{blue}{}{reset} {blue}|{reset} {}\n", line, text);
+                out += &format!("{gutter} {blue}|{reset} {}{accent}{}{reset}\n", " ".repeat(col - 1), "^".repeat(width));
+            }
+            (Some(line), None) => out += &format!("{gutter}{blue}-->{reset} {}:{}\n", file, line),
+            (None, _) => out += &format!("{gutter}{blue}-->{reset} {}\n", file),
+        }
+    }
+
+    for location in error.include_stack.iter().rev() {
+        out += &format!("{gutter} {blue}={reset} included from {}\n", location);
+    }
+    out
+}
+
+/// Renders `error` as a single JSON object:
+/// `{"kind", "message", "file", "line", "column", "directive", "included_from": [{"file", "line"}]}`,
+/// with `null` for what is unknown
+pub fn render_json(error: &PreprocessError) -> String {
+    let or_null = |v: Option<String>| v.unwrap_or_else(|| "null".into());
+    let included_from: Vec<_> = error.include_stack
+        .iter()
+        .map(|l| format!("{{\"file\":{},\"line\":{}}}", json::string(&l.file), l.line))
+        .collect();
+
+    format!(
+        "{{\"kind\":{},\"message\":{},\"file\":{},\"line\":{},\"column\":{},\"directive\":{},\"included_from\":[{}]}}",
+        json::string(error.kind.name()),
+        json::string(&error.message),
+        or_null(error.file.as_deref().map(json::string)),
+        or_null(error.line.map(|l| l.to_string())),
+        or_null(error.directive.as_deref().map(|d| column(d).to_string())),
+        or_null(error.directive.as_deref().map(json::string)),
+        included_from.join(","),
+    )
+}
+
+/// Like [`render_json`], for a warning, with a leading `"severity": "warning"`
+pub fn render_json_warning(warning: &PreprocessError) -> String {
+    format!("{{\"severity\":\"warning\",{}", &render_json(warning)[1..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{Location, ErrorKind};
+
+    #[test]
+    fn render_error() {
+        let error = PreprocessError::new(ErrorKind::Fetch, "file not found <b.txt>")
+            .in_file("test/a.txt")
+            .at_line(12, "  //&include <b.txt>")
+            .included_from(vec![Location::new("test/d.txt", 2)]);
+
+        assert_eq!(render(&error, false), "\
+error: file not found <b.txt>
+  --> test/a.txt:12:3
+   |
+12 |   //&include <b.txt>
+   |   ^^^^^^^^^^^^^^^^^^
+   = included from test/d.txt:2
+");
+        assert_eq!(render_json(&error), "{\"kind\":\"fetch\",\"message\":\"file not found <b.txt>\",\
+\"file\":\"test/a.txt\",\"line\":12,\"column\":3,\"directive\":\"  //&include <b.txt>\",\
+\"included_from\":[{\"file\":\"test/d.txt\",\"line\":2}]}");
+
+        let error = PreprocessError::new(ErrorKind::Build, "empty dependency tree");
+        assert_eq!(render(&error, false), "error: empty dependency tree\n");
+        assert_eq!(render_warning(&error, false), "warning: empty dependency tree\n");
+        assert!(render_json_warning(&error).starts_with("{\"severity\":\"warning\",\"kind\":\"build\","));
+        assert!(render_json(&error).contains("\"file\":null,\"line\":null"));
+    }
+}
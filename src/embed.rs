@@ -0,0 +1,119 @@
+use std::fmt::Write;
+
+use crate::sourcemap::BASE64;
+
+/// How `&embed` writes the bytes of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// `iVBORw0KGgo=`, padded standard base64
+    Base64,
+    /// `89504e47`
+    Hex,
+    /// `{0x89, 0x50, 0x4e, 0x47}`
+    CArray,
+    /// `b"\x89PNG"`
+    RustBytes,
+    /// `"text\n"`, the file must be UTF-8. Other control characters are written as `\x1b`, which
+    /// C would continue with a following hex digit, so that case is rejected in favour of `c-array`.
+    StringLiteral,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "base64" => Some(Encoding::Base64),
+            "hex" => Some(Encoding::Hex),
+            "c-array" => Some(Encoding::CArray),
+            "rust-bytes" => Some(Encoding::RustBytes),
+            "string-literal" => Some(Encoding::StringLiteral),
+            _ => None,
+        }
+    }
+
+    /// Encodes `bytes` as a single line
+    pub fn encode(&self, bytes: &[u8]) -> Result<String, String> {
+        let mut out = String::new();
+        match self {
+            Encoding::Base64 => {
+                for chunk in bytes.chunks(3) {
+                    let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+                    for i in 0..4 {
+                        if i <= chunk.len() {
+                            out.push(BASE64[(n >> (18 - 6 * i) & 0b111111) as usize] as char);
+                        } else {
+                            out.push('=');
+                        }
+                    }
+                }
+            }
+            Encoding::Hex => bytes.iter().for_each(|b| write!(out, "{:02x}", b).unwrap()),
+            Encoding::CArray => {
+                let values: Vec<_> = bytes.iter().map(|b| format!("0x{:02x}", b)).collect();
+                write!(out, "{{{}}}", values.join(", ")).unwrap();
+            }
+            Encoding::RustBytes => {
+                out.push_str("b\"");
+                for b in bytes {
+                    match b {
+                        b'"' => out.push_str("\\\""),
+                        b'\\' => out.push_str("\\\\"),
+                        b'\n' => out.push_str("\\n"),
+                        b'\r' => out.push_str("\\r"),
+                        b'\t' => out.push_str("\\t"),
+                        b' '..=b'~' => out.push(*b as char),
+                        _ => write!(out, "\\x{:02x}", b).unwrap(),
+                    }
+                }
+                out.push('"');
+            }
+            Encoding::StringLiteral => {
+                let text = std::str::from_utf8(bytes).map_err(|_| "can't embed as `string-literal`, the file isn't valid UTF-8")?;
+                out.push('"');
+                let mut chars = text.chars().peekable();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        '\n' => out.push_str("\\n"),
+                        '\r' => out.push_str("\\r"),
+                        '\t' => out.push_str("\\t"),
+                        c if c.is_ascii_control() => {
+                            if chars.peek().is_some_and(char::is_ascii_hexdigit) {
+                                return Err(format!(
+                                    "can't embed as `string-literal`, the escape `\\x{:02x}` would take in the hex digit after it in C, use `c-array` instead",
+                                    c as u32,
+                                ));
+                            }
+                            write!(out, "\\x{:02x}", c as u32).unwrap();
+                        }
+                        c => out.push(c),
+                    }
+                }
+                out.push('"');
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodings() {
+        let bytes = b"\x89PNG\r\n\"\\";
+        assert_eq!(Encoding::Base64.encode(bytes).unwrap(), "iVBORw0KIlw=");
+        assert_eq!(Encoding::Base64.encode(b"ab").unwrap(), "YWI=");
+        assert_eq!(Encoding::Base64.encode(b"abc").unwrap(), "YWJj");
+        assert_eq!(Encoding::Base64.encode(b"a").unwrap(), "YQ==");
+        assert_eq!(Encoding::Hex.encode(bytes).unwrap(), "89504e470d0a225c");
+        assert_eq!(Encoding::CArray.encode(b"\x00\xff").unwrap(), "{0x00, 0xff}");
+        assert_eq!(Encoding::RustBytes.encode(bytes).unwrap(), "b\"\\x89PNG\\r\\n\\\"\\\\\"");
+        assert_eq!(Encoding::StringLiteral.encode(b"say \"hi\"\n").unwrap(), "\"say \\\"hi\\\"\\n\"");
+        assert_eq!(Encoding::StringLiteral.encode("\u{1b}[0m\tä\u{7f}".as_bytes()).unwrap(), "\"\\x1b[0m\\tä\\x7f\"");
+        assert!(Encoding::StringLiteral.encode(bytes).is_err());
+        assert!(Encoding::StringLiteral.encode(b"\x01A").unwrap_err().contains("`\\x01`"));
+        assert_eq!(Encoding::StringLiteral.encode(b"\x01Z").unwrap(), "\"\\x01Z\"");
+    }
+}
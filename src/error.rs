@@ -0,0 +1,100 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A file could not be found or read
+    Fetch,
+    /// A directive could not be parsed or evaluated
+    Parse,
+    /// A file (indirectly) includes itself
+    Cycle,
+    /// The dependency tree could not be flattened
+    Build,
+    /// Raised by an `&error` or `&warning` directive
+    Directive,
+}
+
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Fetch => "fetch",
+            ErrorKind::Parse => "parse",
+            ErrorKind::Cycle => "cycle",
+            ErrorKind::Build => "build",
+            ErrorKind::Directive => "directive",
+        }
+    }
+}
+
+/// A line in a file, the line is 1-based
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+}
+
+impl Location {
+    pub fn new(file: &str, line: usize) -> Location {
+        Location { file: file.to_owned(), line }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreprocessError {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// Resolved name of the file the error occurred in
+    pub file: Option<String>,
+    /// 1-based line the error occurred on
+    pub line: Option<usize>,
+    /// Text of the offending directive line
+    pub directive: Option<String>,
+    /// The includes which led to the file, outermost first
+    pub include_stack: Vec<Location>,
+}
+
+impl PreprocessError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> PreprocessError {
+        PreprocessError { kind, message: message.into(), file: None, line: None, directive: None, include_stack: Vec::new() }
+    }
+
+    pub fn in_file(mut self, file: &str) -> PreprocessError {
+        self.file = Some(file.to_owned());
+        self
+    }
+
+    /// Sets the 1-based line and the text of the directive on it
+    pub fn at_line(mut self, line: usize, directive: &str) -> PreprocessError {
+        self.line = Some(line);
+        self.directive = Some(directive.to_owned());
+        self
+    }
+
+    pub fn included_from(mut self, include_stack: Vec<Location>) -> PreprocessError {
+        self.include_stack = include_stack;
+        self
+    }
+}
+
+impl fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: {}", file, line, self.message)?,
+            (Some(file), None) => write!(f, "{}: {}", file, self.message)?,
+            (None, Some(line)) => write!(f, "line {}: {}", line, self.message)?,
+            (None, None) => write!(f, "{}", self.message)?,
+        }
+        for location in self.include_stack.iter().rev() {
+            write!(f, "\n    included from {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PreprocessError {}
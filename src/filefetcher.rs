@@ -3,7 +3,7 @@ use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Display;
 use std::path::{Path, PathBuf};
-use std::fs::read_to_string;
+use std::fs::{read, read_to_string, read_dir};
 use std::iter;
 
 use normpath::{PathExt, BasePath};
@@ -19,6 +19,12 @@ impl FetchedFile {
     }
 }
 
+/// A file fetched as bytes, e.g. for `&embed`
+pub struct FetchedBytes {
+    pub name: String,
+    pub content: Vec<u8>,
+}
+
 #[derive(Debug, Clone)]
 pub enum FileName {
     Global(String),
@@ -40,43 +46,219 @@ pub trait FileFetcher {
 
     /// Tries to find the file and if it does, resolve an unique name
     fn resolve_name(&mut self, name: &FileName) -> Option<String>;
+
+    /// Like [`FileFetcher::fetch`], but returns the bytes of files which aren't text
+    fn fetch_bytes(&mut self, name: &FileName) -> Option<FetchedBytes> {
+        self.fetch(name).map(|f| FetchedBytes { name: f.name, content: f.content.into_bytes() })
+    }
+
+    /// Existing directories in which `name` is looked up, so that a build can be redone when
+    /// a file appears there, e.g. a missing optional include or a new match of a glob
+    fn search_dirs(&mut self, _name: &FileName) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The files matching the glob pattern `pattern`, sorted by name. `*`, `?` and `[...]` may
+    /// only be used in the last component of the pattern and never match a `/`.
+    fn glob(&mut self, _pattern: &FileName) -> Vec<FileName> {
+        Vec::new()
+    }
+
+    /// The path on disk of the resolved name or search directory `name`, which a dependency
+    /// file can list, or `None` if it isn't on disk
+    fn disk_path(&self, name: &str) -> Option<String> {
+        Some(name.to_owned())
+    }
 }
 
-pub struct MemoryFetcher(HashMap<String, String>);
+/// Whether `name` is a glob pattern rather than a file name
+pub(crate) fn is_glob(name: &str) -> bool {
+    name.contains(['*', '?', '['])
+}
+
+/// Matches `name` against the glob `pattern`, wildcards don't match `/`
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut pattern_chars = pattern.chars();
+    match pattern_chars.next() {
+        None => name.is_empty(),
+        Some('*') => {
+            let rest = pattern_chars.as_str();
+            name.char_indices()
+                .take_while(|(_, c)| *c != '/')
+                .map(|(i, _)| i)
+                .chain(iter::once(name.find('/').unwrap_or(name.len())))
+                .any(|i| glob_match(rest, &name[i..]))
+        }
+        Some('?') => {
+            let mut name_chars = name.chars();
+            matches!(name_chars.next(), Some(c) if c != '/') && glob_match(pattern_chars.as_str(), name_chars.as_str())
+        }
+        Some('[') if pattern_chars.as_str().contains(']') => {
+            let (class, rest) = pattern_chars.as_str().split_once(']').unwrap();
+            let (negated, class) = match class.strip_prefix('!') {
+                Some(class) => (true, class),
+                None => (false, class),
+            };
+            let mut name_chars = name.chars();
+            let Some(c) = name_chars.next().filter(|c| *c != '/') else {
+                return false;
+            };
+            let class: Vec<char> = class.chars().collect();
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    matched |= (class[i]..=class[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            matched != negated && glob_match(rest, name_chars.as_str())
+        }
+        Some(p) => {
+            let mut name_chars = name.chars();
+            name_chars.next() == Some(p) && glob_match(pattern_chars.as_str(), name_chars.as_str())
+        }
+    }
+}
+
+/// Normalizes a `/`-separated path, removing `.` components and `..` components after a
+/// directory. An absolute path stays absolute, the root of a relative path is `""`.
+fn normalize_key(path: &str) -> String {
+    let absolute = path.starts_with('/');
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." if components.last().is_some_and(|c| *c != "..") => {
+                components.pop();
+            }
+            // nothing is above the root of an absolute path
+            ".." if absolute => {}
+            component => components.push(component),
+        }
+    }
+    let path = components.join("/");
+    if absolute { format!("/{}", path) } else { path }
+}
+
+/// Joins `name` to the directory `dir` like [`Path::join`], without normalizing
+fn join_key(dir: &str, name: &str) -> String {
+    if dir.is_empty() || name.starts_with('/') {
+        name.to_owned()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// The directory of the normalized path `path`, `""` for the root
+fn parent_key(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) => "/",
+        Some(i) => &path[..i],
+        None => "",
+    }
+}
+
+/// Files kept in memory, under `/`-separated path-like names.
+///
+/// Names are resolved like [`FilesystemFetcher`] resolves them from the current directory: the
+/// directories of the files exist implicitly, `<name>` is looked up in the search paths and then
+/// the root, `<./name>` and absolute names only at that path, and `"name"` relative to the
+/// directory of the including file. Resolved names are the normalized paths of the files.
+#[derive(Default)]
+pub struct MemoryFetcher {
+    files: HashMap<String, String>,
+    search_order: Vec<String>,
+}
 
 impl MemoryFetcher {
     pub fn new() -> MemoryFetcher {
-        MemoryFetcher(HashMap::new())
+        MemoryFetcher { files: HashMap::new(), search_order: Vec::new() }
     }
 
     pub fn add_file(&mut self, name: &str, data: &str) {
-        self.0.insert(name.to_owned(), data.to_owned());
+        self.files.insert(normalize_key(name), data.to_owned());
+    }
+
+    pub fn add_path(&mut self, p: &str) {
+        self.search_order.push(normalize_key(p));
+    }
+
+    /// Whether `dir` is the root or the directory of some file
+    fn is_dir(&self, dir: &str) -> bool {
+        dir.is_empty() || dir == "/" || self.files.keys().any(|name| name.starts_with(dir) && name[dir.len()..].starts_with('/'))
+    }
+
+    /// The normalized paths `name` is looked up at, in search order
+    fn candidates(&self, name: &FileName) -> Vec<String> {
+        match name {
+            FileName::Global(name) if name.starts_with('/') || name.starts_with("./") => vec![normalize_key(name)],
+            FileName::Global(name) => self.search_order
+                .iter()
+                .map(String::as_str)
+                .chain(iter::once(""))
+                .map(|dir| normalize_key(&join_key(dir, name)))
+                .collect(),
+            FileName::LocalTo(name, local) => {
+                let local = normalize_key(local);
+                let local_parent = if self.files.contains_key(&local) { parent_key(&local) } else { &local };
+                vec![normalize_key(&join_key(local_parent, name))]
+            }
+        }
     }
 }
 
 impl FileFetcher for MemoryFetcher {
     fn fetch(&mut self, name: &FileName) -> Option<FetchedFile> {
-        if let FileName::Global(name) = name {
-            if let Some(source) = self.0.get(name) {
-                Some(FetchedFile::new(name.clone(), source.clone()))
-            } else {
-                None
-            } 
-        } else {
-            todo!("implement local-to for MemoryFetcher.fetch()")
-        }
+        let name = self.resolve_name(name)?;
+        let source = self.files[&name].clone();
+        Some(FetchedFile::new(name, source))
     }
 
     fn resolve_name(&mut self, name: &FileName) -> Option<String> {
-        if let FileName::Global(name) = name {
-            if self.0.contains_key(name) {
-                Some(name.to_owned())
-            } else {
-                None
+        self.candidates(name).into_iter().find(|candidate| self.files.contains_key(candidate))
+    }
+
+    fn search_dirs(&mut self, name: &FileName) -> Vec<String> {
+        let mut dirs = Vec::new();
+        for candidate in self.candidates(name) {
+            let dir = parent_key(&candidate);
+            if !self.is_dir(dir) {
+                continue;
+            }
+            let dir = if dir.is_empty() { "." } else { dir };
+            if !dirs.iter().any(|d| d == dir) {
+                dirs.push(dir.to_owned());
             }
-        } else {
-            todo!("implement local-to for MemoryFetcher.resolve_name()")
         }
+        dirs
+    }
+
+    fn glob(&mut self, pattern: &FileName) -> Vec<FileName> {
+        // the first search path containing the directory of the pattern is used
+        let Some(pattern) = self.candidates(pattern).into_iter().find(|candidate| self.is_dir(parent_key(candidate))) else {
+            return Vec::new();
+        };
+        let mut matches: Vec<_> = self.files.keys()
+            .filter(|name| parent_key(name) == parent_key(&pattern) && glob_match(&pattern, name))
+            .cloned()
+            .collect();
+        matches.sort();
+        // a match is named so that it can't resolve to a file in a search path instead
+        matches
+            .into_iter()
+            .map(|name| {
+                let plain = FileName::Global(name.clone());
+                if self.resolve_name(&plain).as_ref() == Some(&name) { plain } else { FileName::Global(format!("./{}", name)) }
+            })
+            .collect()
+    }
+
+    fn disk_path(&self, _name: &str) -> Option<String> {
+        None
     }
 }
 
@@ -100,6 +282,12 @@ pub struct FilesystemFetcher {
     default: SearchPath,
 }
 
+impl Default for FilesystemFetcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FilesystemFetcher {
     pub fn new() -> FilesystemFetcher {
         FilesystemFetcher {
@@ -115,12 +303,16 @@ impl FilesystemFetcher {
 
 impl FileFetcher for FilesystemFetcher {
     fn fetch(&mut self, name: &FileName) -> Option<FetchedFile> {
-        if let Some(fname) = self.resolve_name(name) {
-            let source = read_to_string(&fname).expect("file exists");
-            Some(FetchedFile::new(fname, source))
-        } else {
-            None
-        }
+        let fname = self.resolve_name(name)?;
+        // files which can't be read as text are treated as missing
+        let source = read_to_string(&fname).ok()?;
+        Some(FetchedFile::new(fname, source))
+    }
+
+    fn fetch_bytes(&mut self, name: &FileName) -> Option<FetchedBytes> {
+        let fname = self.resolve_name(name)?;
+        let content = read(&fname).ok()?;
+        Some(FetchedBytes { name: fname, content })
     }
 
     fn resolve_name(&mut self, name: &FileName) -> Option<String> {
@@ -149,7 +341,7 @@ impl FileFetcher for FilesystemFetcher {
                         let spath = BasePath::new(search_path.get_path().as_path()).unwrap();
                         let joined_path = spath.join(path);
 
-                        if let Some(cp) = joined_path.normalize().ok() {
+                        if let Ok(cp) = joined_path.normalize() {
                             let cp_str = cp.as_path().to_str().unwrap();
                             return Some(cp_str.to_owned());
                         }
@@ -170,4 +362,333 @@ impl FileFetcher for FilesystemFetcher {
             }
         }
     }
+
+    fn search_dirs(&mut self, name: &FileName) -> Vec<String> {
+        let mut dirs = Vec::new();
+        for candidate in self.candidates(name) {
+            let dir = candidate.parent().map(|p| if p.as_os_str().is_empty() { Path::new(".") } else { p });
+            if let Some(dir) = dir.and_then(|d| d.normalize().ok()).and_then(|d| d.as_path().to_str().map(str::to_owned)) {
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+        }
+        dirs
+    }
+
+    fn glob(&mut self, pattern: &FileName) -> Vec<FileName> {
+        // the first search path containing the directory of the pattern is used
+        for candidate in self.candidates(pattern) {
+            let (Some(dir), Some(file_pattern)) = (candidate.parent(), candidate.file_name().and_then(|f| f.to_str())) else {
+                continue;
+            };
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            let Ok(entries) = read_dir(dir) else {
+                continue;
+            };
+
+            let mut matches: Vec<String> = entries
+                .flatten()
+                .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+                .filter(|entry| entry.file_name().to_str().is_some_and(|name| glob_match(file_pattern, name)))
+                .filter_map(|entry| entry.path().normalize().ok())
+                .filter_map(|path| path.as_path().to_str().map(str::to_owned))
+                .collect();
+            matches.sort();
+            return matches.into_iter().map(FileName::Global).collect();
+        }
+        Vec::new()
+    }
+}
+
+impl FilesystemFetcher {
+    /// The paths `name` is looked up at, in search order
+    fn candidates(&self, name: &FileName) -> Vec<PathBuf> {
+        match name {
+            FileName::Global(name) if Path::new(name).is_absolute() || name.starts_with("./") => vec![PathBuf::from(name)],
+            FileName::Global(name) => self.search_order
+                .iter()
+                .chain(iter::once(&self.default))
+                .map(|search_path| search_path.get_path().join(name))
+                .collect(),
+            FileName::LocalTo(name, local) => {
+                let local = Path::new(local);
+                let local_parent = if local.is_file() { local.parent().unwrap_or(local) } else { local };
+                vec![local_parent.join(name)]
+            }
+        }
+    }
+}
+
+/// Tries several fetchers in order, e.g. generated files in memory before the filesystem before
+/// a vendored copy.
+///
+/// Names resolved by a layer are prefixed with its namespace, as in `gen:config.h`, so that files
+/// of different layers are never confused. The names of a layer with an empty namespace are kept
+/// as they are. Local includes are resolved in the layer holding the including file, other names
+/// are looked up in all layers in order. Dependency files list the paths on disk reported by the
+/// layers, without the namespace.
+#[derive(Default)]
+pub struct OverlayFetcher {
+    layers: Vec<(String, Box<dyn FileFetcher>)>,
+}
+
+impl OverlayFetcher {
+    pub fn new() -> OverlayFetcher {
+        OverlayFetcher { layers: Vec::new() }
+    }
+
+    /// Adds a layer which is tried after the existing ones. Panics if `namespace` is already used.
+    pub fn add_layer(&mut self, namespace: &str, fetcher: impl FileFetcher + 'static) {
+        assert!(!self.layers.iter().any(|(ns, _)| ns == namespace), "namespace `{}` is already used by a layer", namespace);
+        self.layers.push((namespace.to_owned(), Box::new(fetcher)));
+    }
+
+    fn namespaced(&self, layer: usize, name: String) -> String {
+        match self.layers[layer].0.as_str() {
+            "" => name,
+            namespace => format!("{}:{}", namespace, name),
+        }
+    }
+
+    /// `name` pinned to a layer, as `namespace:name`, which is `:name` for the empty namespace
+    fn pinned(&self, layer: usize, name: &str) -> String {
+        format!("{}:{}", self.layers[layer].0, name)
+    }
+
+    /// The layer a name is pinned to by its `namespace:` prefix, with the name within that layer
+    fn split<'a>(&self, name: &'a str) -> Option<(usize, &'a str)> {
+        self.layers.iter().enumerate().find_map(|(i, (namespace, _))| Some((i, name.strip_prefix(namespace.as_str())?.strip_prefix(':')?)))
+    }
+
+    /// Whether `name` is a file of the layer `layer`, a directory isn't
+    fn holds(&mut self, layer: usize, name: &str) -> bool {
+        let Some(file) = Path::new(name).file_name().and_then(|f| f.to_str()) else {
+            return false;
+        };
+        self.layers[layer].1.resolve_name(&FileName::LocalTo(file.to_owned(), name.to_owned())).as_deref() == Some(name)
+    }
+
+    /// The layers `name` is looked up in, in order, with the name to look up in each
+    fn candidates(&mut self, name: &FileName) -> Vec<(usize, FileName)> {
+        let pinned = match name {
+            FileName::Global(name) => self.split(name).map(|(i, name)| (i, FileName::Global(name.to_owned()))),
+            FileName::LocalTo(lname, local) => match self.split(local) {
+                Some((i, local)) => Some((i, FileName::LocalTo(lname.clone(), local.to_owned()))),
+                // un-namespaced files are those of the layer with the empty namespace, any layer
+                // may be local to something else, e.g. the directory a build is seeded from
+                None => match self.layers.iter().position(|(namespace, _)| namespace.is_empty()) {
+                    Some(i) if self.holds(i, local) => Some((i, name.clone())),
+                    _ => None,
+                },
+            },
+        };
+        match pinned {
+            Some(pinned) => vec![pinned],
+            None => (0..self.layers.len()).map(|i| (i, name.clone())).collect(),
+        }
+    }
+
+    /// The first layer which finds `name`, with the name to fetch from it
+    fn locate(&mut self, name: &FileName) -> Option<(usize, FileName, String)> {
+        self.candidates(name).into_iter().find_map(|(i, name)| {
+            let resolved = self.layers[i].1.resolve_name(&name)?;
+            Some((i, name, resolved))
+        })
+    }
+}
+
+impl FileFetcher for OverlayFetcher {
+    fn fetch(&mut self, name: &FileName) -> Option<FetchedFile> {
+        let (layer, name, _) = self.locate(name)?;
+        let FetchedFile { name, content } = self.layers[layer].1.fetch(&name)?;
+        Some(FetchedFile::new(self.namespaced(layer, name), content))
+    }
+
+    fn fetch_bytes(&mut self, name: &FileName) -> Option<FetchedBytes> {
+        let (layer, name, _) = self.locate(name)?;
+        let FetchedBytes { name, content } = self.layers[layer].1.fetch_bytes(&name)?;
+        Some(FetchedBytes { name: self.namespaced(layer, name), content })
+    }
+
+    fn resolve_name(&mut self, name: &FileName) -> Option<String> {
+        let (layer, _, resolved) = self.locate(name)?;
+        Some(self.namespaced(layer, resolved))
+    }
+
+    fn search_dirs(&mut self, name: &FileName) -> Vec<String> {
+        // a file appearing in any of the layers may change what is found
+        let mut dirs = Vec::new();
+        for (i, name) in self.candidates(name) {
+            for dir in self.layers[i].1.search_dirs(&name) {
+                let dir = self.namespaced(i, dir);
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+        }
+        dirs
+    }
+
+    fn glob(&mut self, pattern: &FileName) -> Vec<FileName> {
+        // the first layer with matches is used, the matches are pinned to it
+        for (i, pattern) in self.candidates(pattern) {
+            let matches = self.layers[i].1.glob(&pattern);
+            if !matches.is_empty() {
+                return matches
+                    .into_iter()
+                    .map(|name| match name {
+                        FileName::Global(name) => FileName::Global(self.pinned(i, &name)),
+                        FileName::LocalTo(name, local) => FileName::LocalTo(name, self.pinned(i, &local)),
+                    })
+                    .collect();
+            }
+        }
+        Vec::new()
+    }
+
+    fn disk_path(&self, name: &str) -> Option<String> {
+        let (layer, name) = self.split(name).or_else(|| Some((self.layers.iter().position(|(namespace, _)| namespace.is_empty())?, name)))?;
+        self.layers[layer].1.disk_path(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("*.sql", "001_init.sql"));
+        assert!(glob_match("parts/*.sql", "parts/a.sql"));
+        assert!(!glob_match("parts/*.sql", "parts/sub/a.sql"));
+        assert!(!glob_match("*.sql", "a.sql.bak"));
+        assert!(glob_match("0??_*", "001_init.sql"));
+        assert!(glob_match("[0-9]*.[!b]*", "1.sql"));
+        assert!(!glob_match("[0-9]*", "a.sql"));
+        assert!(!glob_match("[!0-9]*", "1.sql"));
+        assert!(is_glob("parts/*.sql") && !is_glob("parts/a.sql"));
+
+        let mut fetcher = MemoryFetcher::new();
+        ["b.sql", "a.sql", "a.txt", "sub/c.sql"].iter().for_each(|name| fetcher.add_file(name, ""));
+        let matches: Vec<_> = fetcher.glob(&FileName::Global("*.sql".into())).iter().map(|n| n.to_string()).collect();
+        assert_eq!(matches, vec!["<a.sql>", "<b.sql>"]);
+    }
+
+    #[test]
+    fn memory_paths() {
+        assert_eq!(normalize_key("./a/./b/../c.txt"), "a/c.txt");
+        assert_eq!(normalize_key("a/../../b.txt"), "../b.txt");
+        assert_eq!(normalize_key("/../a//b.txt"), "/a/b.txt");
+
+        let mut fetcher = MemoryFetcher::new();
+        fetcher.add_file("./src/main.c", "");
+        fetcher.add_file("include/util.h", "");
+        fetcher.add_file("util.h", "");
+        fetcher.add_path("include");
+        let mut resolve = |name| fetcher.resolve_name(&name);
+        assert_eq!(resolve(FileName::Global("util.h".into())).as_deref(), Some("include/util.h"));
+        assert_eq!(resolve(FileName::Global("./util.h".into())).as_deref(), Some("util.h"));
+        assert_eq!(resolve(FileName::LocalTo("../util.h".into(), "src/main.c".into())).as_deref(), Some("util.h"));
+        assert_eq!(resolve(FileName::LocalTo("main.c".into(), "src".into())).as_deref(), Some("src/main.c"));
+        assert_eq!(resolve(FileName::LocalTo("util.h".into(), "src/main.c".into())), None);
+
+        let matches: Vec<_> = fetcher.glob(&FileName::Global("*.h".into())).iter().map(|n| n.to_string()).collect();
+        assert_eq!(matches, vec!["<include/util.h>"]);
+        let matches: Vec<_> = fetcher.glob(&FileName::Global("./*.h".into())).iter().map(|n| n.to_string()).collect();
+        assert_eq!(matches, vec!["<./util.h>"]);
+    }
+
+    #[test]
+    fn memory_mirrors_filesystem() {
+        let mut memory = MemoryFetcher::new();
+        for name in ["a.txt", "b.txt", "c.txt", "d.txt", "testest/a.txt", "testest/d.txt"] {
+            let name = format!("test/{}", name);
+            memory.add_file(&name, &read_to_string(&name).unwrap());
+        }
+        memory.add_path("test");
+        let mut filesystem = FilesystemFetcher::new();
+        filesystem.add_path("test");
+
+        // the filesystem resolves to absolute paths
+        let cwd = std::env::current_dir().unwrap().normalize().unwrap().as_path().to_str().unwrap().to_owned();
+        let relative = |name: String| match name.strip_prefix(&cwd) {
+            Some("") => ".".to_owned(),
+            Some(name) => name.trim_start_matches('/').to_owned(),
+            None => name,
+        };
+
+        let names = [
+            FileName::Global("a.txt".into()),
+            FileName::Global("testest/d.txt".into()),
+            FileName::Global("./test/testest/../c.txt".into()),
+            FileName::Global("missing.txt".into()),
+            FileName::LocalTo("a.txt".into(), "test/testest/d.txt".into()),
+            FileName::LocalTo("../b.txt".into(), "test/testest/a.txt".into()),
+            FileName::LocalTo("d.txt".into(), "test/testest".into()),
+            FileName::LocalTo("missing.txt".into(), "test/a.txt".into()),
+        ];
+        for name in &names {
+            assert_eq!(memory.resolve_name(name), filesystem.resolve_name(name).map(relative), "resolving {}", name);
+            let fetched = memory.fetch(name).map(|f| (f.name, f.content));
+            assert_eq!(fetched, filesystem.fetch(name).map(|f| (relative(f.name), f.content)), "fetching {}", name);
+            let dirs: Vec<_> = filesystem.search_dirs(name).into_iter().map(relative).collect();
+            assert_eq!(memory.search_dirs(name), dirs, "search directories of {}", name);
+        }
+
+        let pattern = FileName::LocalTo("*.txt".into(), "test/testest/d.txt".into());
+        let matches = |fetcher: &mut dyn FileFetcher| -> Vec<_> {
+            fetcher.glob(&pattern).iter().filter_map(|name| fetcher.resolve_name(name)).map(relative).collect()
+        };
+        assert_eq!(matches(&mut memory), ["test/testest/a.txt", "test/testest/d.txt"]);
+        assert_eq!(matches(&mut filesystem), ["test/testest/a.txt", "test/testest/d.txt"]);
+    }
+
+    #[test]
+    fn overlays() {
+        let mut generated = MemoryFetcher::new();
+        generated.add_file("config.h", "generated");
+        let mut files = MemoryFetcher::new();
+        files.add_file("config.h", "local");
+        files.add_file("main.c", "");
+        let mut vendored = MemoryFetcher::new();
+        vendored.add_file("lib/util.h", "");
+        vendored.add_file("lib/extra.h", "vendored");
+        let mut fetcher = OverlayFetcher::new();
+        fetcher.add_layer("gen", generated);
+        fetcher.add_layer("", files);
+        fetcher.add_layer("vendor", vendored);
+
+        let config = fetcher.fetch(&FileName::Global("config.h".into())).unwrap();
+        assert_eq!((config.name.as_str(), config.content.as_str()), ("gen:config.h", "generated"));
+        let mut resolve = |name| fetcher.resolve_name(&name);
+        assert_eq!(resolve(FileName::Global("lib/util.h".into())).as_deref(), Some("vendor:lib/util.h"));
+        assert_eq!(resolve(FileName::LocalTo("extra.h".into(), "vendor:lib/util.h".into())).as_deref(), Some("vendor:lib/extra.h"));
+        assert_eq!(resolve(FileName::LocalTo("config.h".into(), "main.c".into())).as_deref(), Some("config.h"));
+        assert_eq!(resolve(FileName::LocalTo("lib/util.h".into(), "main.c".into())), None);
+        // a prefix pins a name to a layer, `:` to the one with the empty namespace
+        assert_eq!(resolve(FileName::Global(":config.h".into())).as_deref(), Some("config.h"));
+        assert_eq!(resolve(FileName::Global("vendor:config.h".into())), None);
+
+        assert_eq!(fetcher.search_dirs(&FileName::Global("lib/missing.h".into())), ["vendor:lib"]);
+        let matches: Vec<_> = fetcher.glob(&FileName::Global("lib/*.h".into())).iter().map(|n| n.to_string()).collect();
+        assert_eq!(matches, vec!["<vendor:lib/extra.h>", "<vendor:lib/util.h>"]);
+        let extra = fetcher.fetch_bytes(&FileName::Global("lib/extra.h".into())).unwrap();
+        assert_eq!((extra.name.as_str(), extra.content.as_slice()), ("vendor:lib/extra.h", b"vendored".as_slice()));
+        assert_eq!((fetcher.disk_path("config.h"), fetcher.disk_path("vendor:lib/extra.h")), (None, None));
+
+        // local includes of un-namespaced files stay in the layer with the empty namespace
+        let mut filesystem = FilesystemFetcher::new();
+        filesystem.add_path("test");
+        let mut files = MemoryFetcher::new();
+        files.add_file("test/testest/d.txt", "");
+        let mut fetcher = OverlayFetcher::new();
+        fetcher.add_layer("disk", filesystem);
+        fetcher.add_layer("", files);
+        assert_eq!(fetcher.resolve_name(&FileName::LocalTo("a.txt".into(), "test/testest/d.txt".into())), None);
+        let a = fetcher.resolve_name(&FileName::Global("a.txt".into())).unwrap();
+        let path = a.strip_prefix("disk:").unwrap();
+        assert!(Path::new(path).is_file());
+        assert_eq!(fetcher.disk_path(&a).as_deref(), Some(path));
+    }
 }
@@ -0,0 +1,203 @@
+/// Quotes and escapes `s` as a JSON string
+pub fn string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A parsed JSON value, numbers are kept as `f64`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a JSON document
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser { text, pos: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos == text.len() {
+        Ok(value)
+    } else {
+        Err(parser.error("trailing characters"))
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("invalid JSON at byte {}: {}", self.pos, message)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rem = &self.text[self.pos..];
+        self.pos += rem.len() - rem.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.text[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        let rem = &self.text[self.pos..];
+        match rem.chars().next() {
+            Some('{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if !self.eat("}") {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        if !self.eat(":") {
+                            return Err(self.error("expected `:`"));
+                        }
+                        members.push((key, self.value()?));
+                        if self.eat("}") {
+                            break;
+                        } else if !self.eat(",") {
+                            return Err(self.error("expected `,` or `}`"));
+                        }
+                    }
+                }
+                Ok(Value::Object(members))
+            }
+            Some('[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                if !self.eat("]") {
+                    loop {
+                        values.push(self.value()?);
+                        if self.eat("]") {
+                            break;
+                        } else if !self.eat(",") {
+                            return Err(self.error("expected `,` or `]`"));
+                        }
+                    }
+                }
+                Ok(Value::Array(values))
+            }
+            Some('"') => self.string().map(Value::String),
+            Some(_) if self.eat("null") => Ok(Value::Null),
+            Some(_) if self.eat("true") => Ok(Value::Bool(true)),
+            Some(_) if self.eat("false") => Ok(Value::Bool(false)),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let len = rem.find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c))).unwrap_or(rem.len());
+                let number = rem[..len].parse().map_err(|_| self.error("invalid number"))?;
+                self.pos += len;
+                Ok(Value::Number(number))
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if !self.text[self.pos..].starts_with('"') {
+            return Err(self.error("expected a string"));
+        }
+        self.pos += 1;
+
+        let mut s = String::new();
+        let mut chars = self.text[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(s);
+                }
+                '\\' => match chars.next().map(|(_, e)| e) {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, h)| h).collect();
+                        let code = u32::from_str_radix(&hex, 16).map_err(|_| self.error("invalid escape"))?;
+                        s.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    _ => return Err(self.error("invalid escape")),
+                },
+                c => s.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let text = format!("{{\"name\": {}, \"list\": [1, -2.5, null, true], \"empty\": {{}}}}", string("a \"b\"\n\\c"));
+        let value = parse(&text).unwrap();
+
+        assert_eq!(value.get("name").and_then(Value::as_str), Some("a \"b\"\n\\c"));
+        assert_eq!(value.get("list"), Some(&Value::Array(vec![Value::Number(1.0), Value::Number(-2.5), Value::Null, Value::Bool(true)])));
+        assert_eq!(value.get("empty"), Some(&Value::Object(Vec::new())));
+        assert!(parse("[1, 2").is_err());
+        assert!(parse("{\"a\": 1} x").is_err());
+    }
+}
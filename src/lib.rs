@@ -1,84 +1,676 @@
+use std::borrow::Cow;
 use std::collections::HashSet;
-use std::fmt;
 
 mod process;
 mod filefetcher;
+mod symbols;
+mod presets;
+mod error;
+mod json;
+mod sourcemap;
+mod embed;
+pub mod diagnostics;
 pub mod deps;
 
-use deps::InsertionPoint;
+use deps::{InsertionPoint, Inclusions, FileData, Variant, directive_message};
+use process::{Conditionals, apply_definition, expand_arguments};
 
 pub use deps::{Dependencies, generate_dependencies, create_depfile};
-pub use process::{ParseLine, CommentParser};
-pub use filefetcher::{FileFetcher, FilesystemFetcher, MemoryFetcher};
+pub use process::{ParseLine, CommentParser, BlockCommentParser, PreprocCommand, IncludeOptions, Selection};
+pub use filefetcher::{FileFetcher, FilesystemFetcher, MemoryFetcher, OverlayFetcher};
+pub use symbols::{Symbols, is_identifier};
+pub use presets::{SelectParser, LanguagePresets};
+pub use error::{PreprocessError, ErrorKind, Location};
+pub use sourcemap::{SourceMap, Origin};
+pub use embed::Encoding;
 
-const JOIN_SEPARATOR: &'static str = "\n";
+const JOIN_SEPARATOR: &str = "\n";
 
-#[derive(Debug)]
-pub enum PreprocessError {
-    FetchError(String),
-    ParseError(String),
+/// How often a file is expanded when it's included several times
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IncludePolicy {
+    /// Only the first include of a file is expanded, later ones are dropped
+    #[default]
+    OncePerOutput,
+    /// Every include is expanded, unless the file contains `&once`
+    EveryInclude,
 }
 
-impl fmt::Display for PreprocessError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::FetchError(s) => write!(f, "FetchError({})", s),
-            Self::ParseError(s) => write!(f, "ParseError({})", s),
-        }
-    }
+/// What to do when a file (indirectly) includes itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CycleMode {
+    /// Report the cycle as an error
+    Strict,
+    /// Skip the include closing the cycle, with a warning returned by [`build`]
+    #[default]
+    Lenient,
+}
+
+/// How [`build_file`] marks where the lines of the output came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineMarkers {
+    #[default]
+    None,
+    /// `#line 12 "file"`, as understood by C compilers
+    C,
+    /// `// line 12 "file"`, written with the comment syntax of the file the lines come from.
+    /// Falls back to [`LineMarkers::C`] if the parser of that file doesn't tell its syntax.
+    Comment,
+}
+
+/// Settings shared by [`generate_dependencies`] and [`build_file`]
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Symbols defined before the seed file is processed
+    pub symbols: Symbols,
+    pub policy: IncludePolicy,
+    pub cycles: CycleMode,
+    /// Only used by [`build_file`]
+    pub line_markers: LineMarkers,
+    /// Whether included lines are indented with the whitespace preceding the include directive,
+    /// unless the directive says `indent` or `noindent`. Only used by [`build_file`].
+    pub indent_includes: bool,
+}
+
+/// Flattens the dependency tree into a single source, substituting defined symbols in every
+/// emitted line. `options` must be the same as those given to [`generate_dependencies`].
+///
+/// With line markers enabled a marker precedes every line which doesn't directly follow the
+/// previous line of the output in its original file, e.g. when switching between files.
+pub fn build_file(dependencies: &Dependencies, options: &Options) -> Result<String, PreprocessError> {
+    build(dependencies, options).map(|output| output.source)
 }
 
-pub fn build_file(dependencies: &Dependencies) -> Result<String, String> {
+/// Like [`build_file`], also returning where each line of the output came from
+pub fn build_file_with_map(dependencies: &Dependencies, options: &Options) -> Result<(String, SourceMap), PreprocessError> {
+    build(dependencies, options).map(|output| (output.source, output.map))
+}
+
+/// What [`build`] produces
+#[derive(Debug, Clone)]
+pub struct BuildOutput {
+    pub source: String,
+    /// Where each line of `source` came from
+    pub map: SourceMap,
+    /// Raised by `&warning` directives and skipped include cycles, in output order
+    pub warnings: Vec<PreprocessError>,
+}
+
+/// Like [`build_file`], returning the source map and the warnings along with the output
+pub fn build(dependencies: &Dependencies, options: &Options) -> Result<BuildOutput, PreprocessError> {
     if dependencies.is_empty() {
-        return Err("empty dependency tree".into());
-    }
-    // figure out top scope
-    let mentioned: HashSet<_> = dependencies
-        .values()
-        .map(|d| d.points.iter().map(|p| &p.fname))
-        .flatten()
-        .collect();
-    let sources: HashSet<_> = dependencies.keys().collect();
-
-    let not_mentioned: Vec<_> = sources.difference(&mentioned).map(|s| *s).collect();
-    
-    let roots: Vec<_> = if not_mentioned.is_empty() {
-        dependencies.keys().take(1).collect()
+        return Err(PreprocessError::new(ErrorKind::Build, "empty dependency tree"));
+    }
+    // figure out top scope, if no seed was recorded use the files which aren't included anywhere
+    let roots: Vec<_> = if !dependencies.roots().is_empty() {
+        dependencies.roots().iter().collect()
     } else {
-        not_mentioned
+        let mentioned: HashSet<_> = dependencies
+            .values()
+            .flat_map(|d| d.points.iter().map(|p| &p.fname))
+            .collect();
+        let not_mentioned: Vec<_> = dependencies.keys().filter(|k| !mentioned.contains(k)).collect();
+
+        if not_mentioned.is_empty() {
+            dependencies.keys().take(1).collect()
+        } else {
+            not_mentioned
+        }
     };
 
-    let mut acc = Vec::new();
-    let mut visited = HashSet::new();
+    let mut acc = Output::new(options.line_markers, options.indent_includes);
+    let mut symbols = options.symbols.clone();
+    let mut inclusions = Inclusions::new(options);
 
     for root in roots {
-        subbuild_file(root.clone(), &mut acc, dependencies, &mut visited);
+        if inclusions.should_include(root, Variant::default()) {
+            subbuild_file(root, Variant::default(), "", &mut acc, dependencies, &mut symbols, &mut inclusions)?;
+        }
+    }
+
+    let map = SourceMap {
+        lines: acc.origins.iter().map(|o| o.map(|(fname, i, _)| Location::new(fname, i + 1))).collect(),
+        line_stacks: acc.origins.iter().map(|o| o.map_or(0, |(_, _, stack)| stack)).collect(),
+        include_stacks: acc.stacks,
+    };
+    Ok(BuildOutput { source: acc.lines.as_slice().join(JOIN_SEPARATOR), map, warnings: acc.warnings })
+}
+
+/// The lines emitted by a build
+struct Output<'a> {
+    lines: Vec<Cow<'a, str>>,
+    /// file, index and include stack each line came from, `None` for line markers
+    origins: Vec<Option<(&'a str, usize, usize)>>,
+    /// include stacks of the expanded files, outermost first
+    stacks: Vec<Vec<Location>>,
+    warnings: Vec<PreprocessError>,
+    markers: LineMarkers,
+    indent_includes: bool,
+    /// file and index of the last emitted line
+    last: Option<(&'a str, usize)>,
+}
+
+impl<'a> Output<'a> {
+    fn new(markers: LineMarkers, indent_includes: bool) -> Output<'a> {
+        Output { lines: Vec::new(), origins: Vec::new(), stacks: Vec::new(), warnings: Vec::new(), markers, indent_includes, last: None }
+    }
+
+    /// Records an include stack, returning its index
+    fn add_stack(&mut self, stack: Vec<Location>) -> usize {
+        if self.stacks.last() != Some(&stack) {
+            self.stacks.push(stack);
+        }
+        self.stacks.len() - 1
     }
 
-    Ok(acc.as_slice().join(JOIN_SEPARATOR))
+    /// Appends `line`, which is at `index` of `fname`, preceded by a line marker if needed
+    fn push(&mut self, line: Cow<'a, str>, fname: &'a str, index: usize, data: &'a FileData, stack: usize) {
+        let continues = self.last.is_some_and(|(f, i)| f == fname && i + 1 == index);
+        if !continues {
+            let marker = format!("line {} {}", index + 1, json::string(fname));
+            let marker = match (self.markers, &data.comment) {
+                (LineMarkers::None, _) => None,
+                (LineMarkers::Comment, Some((open, close))) if close.is_empty() => Some(format!("{} {}", open, marker)),
+                (LineMarkers::Comment, Some((open, close))) => Some(format!("{} {} {}", open, marker, close)),
+                (LineMarkers::C | LineMarkers::Comment, _) => Some(format!("#{}", marker)),
+            };
+            if let Some(marker) = marker {
+                self.lines.push(marker.into());
+                self.origins.push(None);
+            }
+        }
+        self.lines.push(line);
+        self.origins.push(Some((fname, index, stack)));
+        self.last = Some((fname, index));
+    }
 }
 
-fn subbuild_file<'a>(fname: String, acc: &mut Vec<&'a str>, dependencies: &'a Dependencies, visited: &mut HashSet<String>) {
-    // get lines and insert-points
-    let deps::FileData { source, points } = dependencies.get(&fname).unwrap();
-    let mut lines = source.lines().enumerate();
-    visited.insert(fname);
-
-    for InsertionPoint {fname: subname, index} in points {
-        loop {
-            let (i, line) = lines.next().unwrap();  // no insertion point should have an index 
-                                                    // greater than the nr of lines in a file
-            if i == *index {
-                if !visited.contains(subname) {
-                    subbuild_file(subname.clone(), acc, dependencies, visited);
+/// Emits the lines of the `variant` of `fname` prefixed with `indent`, except for empty lines
+fn subbuild_file<'a>(fname: &'a str, variant: Variant, indent: &str, acc: &mut Output<'a>, dependencies: &'a Dependencies, symbols: &mut Symbols, inclusions: &mut Inclusions) -> Result<(), PreprocessError> {
+    // get lines, directives and insert-points
+    let data = dependencies.get(fname).unwrap();
+    let FileData { source, points, directives, .. } = data;
+    let lines = data.selected_lines(variant.selection).map_err(|e| inclusions.locate_include(ErrorKind::Fetch, e, dependencies))?;
+    inclusions.enter(fname, variant);
+    let mut conditionals = Conditionals::at_line(directives, lines.start, symbols)
+        .map_err(|(i, e)| inclusions.locate(ErrorKind::Parse, e, dependencies, i))?;
+    let mut directives = directives.iter().skip_while(|(index, _)| *index < lines.start).peekable();
+    let mut stack = inclusions.include_stack();
+    stack.pop();
+    let stack = acc.add_stack(stack);
+
+    for (i, line) in source.lines().enumerate().take(lines.end).skip(lines.start) {
+        if let Some((_, command)) = directives.next_if(|(index, _)| *index == i) {
+            // directive lines are never part of the output
+            let conditional = conditionals.apply(command, symbols)
+                .map_err(|e| inclusions.locate(ErrorKind::Parse, e, dependencies, i))?;
+            if conditional || !conditionals.is_active() || apply_definition(command, symbols) {
+                continue;
+            }
+            if let PreprocCommand::Once = command {
+                inclusions.mark_once(fname);
+            } else if let PreprocCommand::Error(message) = command {
+                return Err(inclusions.locate(ErrorKind::Directive, directive_message(message, "&error", symbols), dependencies, i));
+            } else if let PreprocCommand::Warning(message) = command {
+                let warning = inclusions.locate(ErrorKind::Directive, directive_message(message, "&warning", symbols), dependencies, i);
+                acc.warnings.push(warning);
+            } else if points.iter().any(|p| p.index == i) {
+                // several points share the line when a glob matched several files
+                inclusions.include_at(i);
+                let (PreprocCommand::Include(_, options) | PreprocCommand::IncludeLocal(_, options)) = command else {
+                    unreachable!("insertion points are only created for includes");
+                };
+                let subindent = if options.indent.unwrap_or(acc.indent_includes) {
+                    let own = &line[..line.len() - line.trim_start().len()];
+                    Cow::Owned(format!("{}{}", indent, own))
+                } else {
+                    Cow::Borrowed(indent)
+                };
+                for InsertionPoint { fname: subname, selection, args, .. } in points.iter().filter(|p| p.index == i) {
+                    let args = expand_arguments(args, symbols);
+                    let variant = Variant { selection: selection.as_ref(), args: &args };
+                    if let Some(encoding) = options.embed {
+                        let bytes = dependencies.embedded(subname).unwrap();
+                        let literal = encoding.encode(bytes).map_err(|e| inclusions.locate(ErrorKind::Build, e, dependencies, i))?;
+                        let stack = acc.add_stack(inclusions.include_stack());
+                        acc.push(Cow::Owned(format!("{}{}", subindent, literal)), subname, 0, data, stack);
+                    } else if options.raw {
+                        build_raw(subname, selection.as_ref(), &subindent, acc, dependencies, inclusions)?;
+                    } else if inclusions.should_include(subname, variant) {
+                        let previous = symbols.bind_arguments(&args);
+                        subbuild_file(subname, variant, &subindent, acc, dependencies, symbols, inclusions)?;
+                        symbols.unbind_arguments(previous);
+                    } else if let Some(chain) = inclusions.cycle(subname, variant.selection) {
+                        let message = format!("include cycle {}, skipping the last include", chain);
+                        acc.warnings.push(inclusions.locate(ErrorKind::Cycle, message, dependencies, i));
+                    }
                 }
-                break;
-            } else {
-                acc.push(line)
             }
-        }   
+        } else if conditionals.is_active() {
+            let line = match symbols.expand(line) {
+                line if indent.is_empty() || line.is_empty() => line,
+                line => Cow::Owned(format!("{}{}", indent, line)),
+            };
+            acc.push(line, fname, i, data, stack);
+        }
+    }
+
+    inclusions.leave();
+    Ok(())
+}
+
+/// Emits the `selection` of the lines of `fname` as they are, prefixed with `indent` except for
+/// empty lines
+fn build_raw<'a>(fname: &'a str, selection: Option<&Selection>, indent: &str, acc: &mut Output<'a>, dependencies: &'a Dependencies, inclusions: &Inclusions) -> Result<(), PreprocessError> {
+    let data = &dependencies[fname];
+    let lines = data.selected_lines(selection).map_err(|e| inclusions.locate_include(ErrorKind::Fetch, e, dependencies))?;
+    let stack = acc.add_stack(inclusions.include_stack());
+    for (i, line) in data.source.lines().enumerate().take(lines.end).skip(lines.start) {
+        let line = if indent.is_empty() || line.is_empty() { Cow::Borrowed(line) } else { Cow::Owned(format!("{}{}", indent, line)) };
+        acc.push(line, fname, i, data, stack);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filefetcher::{FileName, FetchedFile};
+
+    /// Memory-backed fetcher which resolves every name from the root
+    struct FlatFetcher(MemoryFetcher);
+
+    impl FlatFetcher {
+        fn new(files: &[(&str, &str)]) -> FlatFetcher {
+            let mut fetcher = MemoryFetcher::new();
+            files.iter().for_each(|(name, data)| fetcher.add_file(name, data));
+            FlatFetcher(fetcher)
+        }
+
+        fn flatten(name: &FileName) -> FileName {
+            match name {
+                FileName::LocalTo(n, _) => FileName::Global(n.clone()),
+                global => global.clone(),
+            }
+        }
+    }
+
+    impl FileFetcher for FlatFetcher {
+        fn fetch(&mut self, name: &FileName) -> Option<FetchedFile> {
+            self.0.fetch(&Self::flatten(name))
+        }
+
+        fn resolve_name(&mut self, name: &FileName) -> Option<String> {
+            self.0.resolve_name(&Self::flatten(name))
+        }
+
+        fn search_dirs(&mut self, _name: &FileName) -> Vec<String> {
+            vec!["root".to_owned()]
+        }
+
+        fn glob(&mut self, pattern: &FileName) -> Vec<FileName> {
+            self.0.glob(&Self::flatten(pattern))
+        }
+    }
+
+    fn build(files: &[(&str, &str)], options: &Options) -> Result<String, PreprocessError> {
+        let mut fetcher = FlatFetcher::new(files);
+        let (_, deps) = generate_dependencies(files[0].0, &mut fetcher, &CommentParser::from("//"), options)?;
+        build_file(&deps, options)
+    }
+
+    #[test]
+    fn excluded_includes() {
+        let files = [
+            ("main.txt", "begin\n//&ifdef DEBUG\n//&include <debug.txt>\n//&else\n//&include <release.txt>\n//&endif\nend"),
+            ("debug.txt", "debug"),
+            ("release.txt", "release"),
+        ];
+
+        let mut fetcher = FlatFetcher::new(&files);
+        let (_, deps) = generate_dependencies("main.txt", &mut fetcher, &CommentParser::from("//"), &Options::default()).unwrap();
+        assert!(!deps.contains_key("debug.txt"));
+        assert_eq!(build_file(&deps, &Options::default()).unwrap(), "begin\nrelease\nend");
+
+        let mut options = Options::default();
+        options.symbols.define("DEBUG", "1");
+        assert_eq!(build(&files, &options).unwrap(), "begin\ndebug\nend");
+    }
+
+    #[test]
+    fn definitions_across_includes() {
+        let files = [
+            ("main.txt", "//&include <version.txt>\nversion VERSION\n//&ifdef RELEASE\nrelease ${VERSION}\n//&endif\n//&undef VERSION\nVERSION"),
+            ("version.txt", "//&define MAJOR 1\n//&define VERSION MAJOR.4.2\n//&define RELEASE"),
+        ];
+        assert_eq!(build(&files, &Options::default()).unwrap(), "version 1.4.2\nrelease 1.4.2\nVERSION");
+    }
+
+    #[test]
+    fn repeated_includes() {
+        let files = [
+            ("main.txt", "//&include <license.txt>\n//&include <header.txt>\nbody\n//&include <license.txt>\n//&include <header.txt>"),
+            ("license.txt", "license"),
+            ("header.txt", "//&once\nheader"),
+        ];
+        assert_eq!(build(&files, &Options::default()).unwrap(), "license\nheader\nbody");
+
+        let options = Options { policy: IncludePolicy::EveryInclude, ..Options::default() };
+        assert_eq!(build(&files, &options).unwrap(), "license\nheader\nbody\nlicense");
+    }
+
+    #[test]
+    fn include_cycles() {
+        let files = [
+            ("a.txt", "a begin\n//&include <b.txt>\na end"),
+            ("b.txt", "b begin\n//&include <c.txt>\nb end"),
+            ("c.txt", "c\n\n//&include <a.txt>"),
+        ];
+        assert_eq!(build(&files, &Options::default()).unwrap(), "a begin\nb begin\nc\n\nb end\na end");
+
+        let mut fetcher = FlatFetcher::new(&files);
+        let (_, deps) = generate_dependencies("a.txt", &mut fetcher, &CommentParser::from("//"), &Options::default()).unwrap();
+        let warnings = super::build(&deps, &Options::default()).unwrap().warnings;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].to_string(), "c.txt:3: include cycle a.txt:2 -> b.txt:2 -> c.txt:3 -> a.txt, skipping the last include\n    \
+            included from b.txt:2\n    included from a.txt:2");
+
+        let options = Options { cycles: CycleMode::Strict, ..Options::default() };
+        let error = build(&files, &options).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Cycle);
+        assert_eq!(error.message, "include cycle a.txt:2 -> b.txt:2 -> c.txt:3 -> a.txt");
+        assert_eq!(error.file.as_deref(), Some("c.txt"));
+        assert_eq!(error.line, Some(3));
+        assert_eq!(error.directive.as_deref(), Some("//&include <a.txt>"));
+        assert_eq!(error.include_stack, vec![Location::new("a.txt", 2), Location::new("b.txt", 2)]);
+    }
+
+    #[test]
+    fn line_markers() {
+        let files = [
+            ("main.c", "int a;\n//&include <inc.h>\nint b;\n//&if 0\nint c;\n//&endif\nint d;"),
+            ("inc.h", "int x;\nint y;"),
+        ];
+        let options = Options { line_markers: LineMarkers::C, ..Options::default() };
+        assert_eq!(build(&files, &options).unwrap(), "#line 1 \"main.c\"\nint a;\n#line 1 \"inc.h\"\nint x;\nint y;\n\
+            #line 3 \"main.c\"\nint b;\n#line 7 \"main.c\"\nint d;");
+
+        let options = Options { line_markers: LineMarkers::Comment, ..Options::default() };
+        assert!(build(&files, &options).unwrap().starts_with("// line 1 \"main.c\"\nint a;\n// line 1 \"inc.h\"\n"));
+    }
+
+    #[test]
+    fn optional_includes() {
+        let files = [("main.txt", "a\n//&include? <settings.txt>\n//&include? <other.txt>\nb"), ("other.txt", "other")];
+        let mut fetcher = FlatFetcher::new(&files);
+        let (_, deps) = generate_dependencies("main.txt", &mut fetcher, &CommentParser::from("//"), &Options::default()).unwrap();
+        assert_eq!(build_file(&deps, &Options::default()).unwrap(), "a\nother\nb");
+        assert_eq!(create_depfile("main.i", None, &deps), "main.i: main.txt other.txt root");
+
+        let files = [("main.txt", "//&include <settings.txt>")];
+        assert_eq!(build(&files, &Options::default()).unwrap_err().kind, ErrorKind::Fetch);
+    }
+
+    #[test]
+    fn partial_includes() {
+        let files = [
+            ("doc.md", "Setup:\n//&include <app.c> region setup\nLines:\n//&include <app.c> lines 1-2\n//&verbatim <app.c> lines 2-2\nAgain:\n//&include <app.c> region setup"),
+            ("app.c", "int main() {\n//&region setup\n    init();\n//&endregion\n    run();\n}"),
+        ];
+        let options = Options::default();
+        assert_eq!(build(&files, &options).unwrap(), "Setup:\n    init();\nLines:\nint main() {\n//&region setup\nAgain:");
+
+        let options = Options { line_markers: LineMarkers::C, ..Options::default() };
+        assert!(build(&files, &options).unwrap().starts_with("#line 1 \"doc.md\"\nSetup:\n#line 3 \"app.c\"\n    init();\n"));
+
+        // a selection may start and end inside a conditional block
+        let files = [("doc.md", "//&include <app.c> lines 3-4\n//&include <app.c> lines 2-2"), ("app.c", "//&ifdef X\nint x;\nint y;\n//&endif")];
+        assert_eq!(build(&files, &Options::default()).unwrap(), "");
+        let mut symbols = Symbols::new();
+        symbols.define("X", "1");
+        let options = Options { symbols, ..Options::default() };
+        assert_eq!(build(&files, &options).unwrap(), "int y;\nint x;");
+
+        let files = [("doc.md", "text\n//&include <app.c> region other"), ("app.c", "int x;")];
+        let error = build(&files, &Options::default()).unwrap_err();
+        assert_eq!(error.to_string(), "doc.md:2: region `other` not found");
+    }
+
+    #[test]
+    fn parameterised_includes() {
+        let files = [
+            ("schema.sql", "//&define T accounts\n//&include <table.sql> name=users pk=id\n//&include <table.sql> name=T pk=\"account id\"\n//&include <table.sql> name=users pk=id\nname"),
+            ("table.sql", "CREATE TABLE ${name} (${pk});"),
+        ];
+        let options = Options::default();
+        assert_eq!(build(&files, &options).unwrap(), "CREATE TABLE users (id);\nCREATE TABLE accounts (account id);\nname");
+
+        let options = Options { policy: IncludePolicy::EveryInclude, ..Options::default() };
+        assert_eq!(build(&files, &options).unwrap(), "CREATE TABLE users (id);\nCREATE TABLE accounts (account id);\nCREATE TABLE users (id);\nname");
+
+        // `&once` applies whatever the arguments and the policy
+        let files = [("main.c", "//&include <h> a=1\n//&include <h> a=2\n//&include <h> lines 2-2"), ("h", "//&once\nheader ${a}")];
+        assert_eq!(build(&files, &Options::default()).unwrap(), "header 1");
+        assert_eq!(build(&files, &options).unwrap(), "header 1");
+
+        // arguments are only substituted as `${}`, and definitions made by the template outlive it
+        let files = [
+            ("main.sql", "//&include <table.sql> name=users pk=id\n${name} LAST"),
+            ("table.sql", "//&define LAST ${name}\nCREATE TABLE ${name} (${pk} INTEGER PRIMARY KEY, name TEXT);"),
+        ];
+        assert_eq!(build(&files, &Options::default()).unwrap(), "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);\n${name} users");
+    }
+
+    #[test]
+    fn embeds() {
+        let files = [
+            ("main.c", "const char logo[] =\n    //&embed <logo.bin> as c-array indent\n;\n//&embed <logo.bin> as hex\n//&embed <logo.bin> as string-literal"),
+            ("logo.bin", "\u{1}Z"),
+        ];
+        let mut fetcher = FlatFetcher::new(&files);
+        let (_, deps) = generate_dependencies("main.c", &mut fetcher, &CommentParser::from("//"), &Options::default()).unwrap();
+        assert_eq!(deps.keys().collect::<Vec<_>>(), ["main.c"]);
+        assert_eq!(create_depfile("main.i", None, &deps), "main.i: main.c logo.bin");
+        assert_eq!(build_file(&deps, &Options::default()).unwrap(), "const char logo[] =\n    {0x01, 0x5a}\n;\n015a\n\"\\x01Z\"");
     }
 
-    // append remaining lines
-    lines.for_each(|(_, l)| acc.push(l));
-}
\ No newline at end of file
+    #[test]
+    fn raw_includes() {
+        let files = [
+            ("main.md", "# Usage\n    //&verbatim <example.txt>\n//&include_raw <example.txt>\n//&include_raw <snippet.txt>\n//&include <snippet.txt>"),
+            ("example.txt", "//&include <missing.txt>\n//&if"),
+            ("snippet.txt", "//&define X 1\nX"),
+        ];
+        let options = Options { indent_includes: true, ..Options::default() };
+        let mut fetcher = FlatFetcher::new(&files);
+        let (_, deps) = generate_dependencies("main.md", &mut fetcher, &CommentParser::from("//"), &options).unwrap();
+        assert_eq!(deps.keys().collect::<Vec<_>>(), ["main.md", "example.txt", "snippet.txt"]);
+        assert_eq!(
+            build_file(&deps, &options).unwrap(),
+            "# Usage\n    //&include <missing.txt>\n    //&if\n//&include <missing.txt>\n//&if\n//&define X 1\nX\n1",
+        );
+    }
+
+    #[test]
+    fn glob_includes() {
+        let files = [
+            ("main.sql", "begin;\n//&include <parts/*.sql>\n//&include? <extra/*.sql>\ncommit;"),
+            ("parts/002_users.sql", "create users;"),
+            ("parts/001_init.sql", "create schema;"),
+            ("parts/notes.txt", "notes"),
+        ];
+        let mut fetcher = FlatFetcher::new(&files);
+        let (_, deps) = generate_dependencies("main.sql", &mut fetcher, &CommentParser::from("//"), &Options::default()).unwrap();
+        assert_eq!(build_file(&deps, &Options::default()).unwrap(), "begin;\ncreate schema;\ncreate users;\ncommit;");
+        assert_eq!(deps["main.sql"].points.len(), 2);
+        assert_eq!(deps.watched(), ["root"]);
+
+        let files = [("main.sql", "//&include <parts/*.sql>")];
+        assert_eq!(build(&files, &Options::default()).unwrap_err().message, "no files match <parts/*.sql>");
+
+        // names which merely look like globs
+        let files = [("main.sql", "//&include <a[1].sql>\n//&include <what?.sql>"), ("a[1].sql", "one"), ("what?.sql", "two"), ("a1.sql", "glob")];
+        assert_eq!(build(&files, &Options::default()).unwrap(), "one\ntwo");
+    }
+
+    #[test]
+    fn indented_includes() {
+        let files = [
+            ("main.py", "def main():\n    //&include <body.py>\n    //&include <body.py> noindent\n\n//&include <body.py> indent"),
+            ("body.py", "if x:\n\n    //&include <call.py> indent"),
+            ("call.py", "call()"),
+        ];
+        let options = Options { policy: IncludePolicy::EveryInclude, indent_includes: true, ..Options::default() };
+        assert_eq!(
+            build(&files, &options).unwrap(),
+            "def main():\n    if x:\n\n        call()\nif x:\n\n    call()\n\nif x:\n\n    call()",
+        );
+
+        let options = Options { policy: IncludePolicy::EveryInclude, ..Options::default() };
+        assert!(build(&files, &options).unwrap().starts_with("def main():\nif x:\n\n    call()\n"));
+    }
+
+    #[test]
+    fn error_and_warning_directives() {
+        let files = [
+            ("main.txt", "//&define NAME main\n//&include <config.txt>\ntext"),
+            ("config.txt", "//&ifndef FEATURE_X\n//&error NAME requires FEATURE_X\n//&endif\n//&warning config for NAME is deprecated\n//&warning"),
+        ];
+        let error = build(&files, &Options::default()).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Directive);
+        assert_eq!(error.to_string(), "config.txt:2: main requires FEATURE_X\n    included from main.txt:2");
+
+        let mut options = Options::default();
+        options.symbols.define("FEATURE_X", "1");
+        let mut fetcher = FlatFetcher::new(&files);
+        let (_, deps) = generate_dependencies("main.txt", &mut fetcher, &CommentParser::from("//"), &options).unwrap();
+        let output = super::build(&deps, &options).unwrap();
+        assert_eq!(output.source, "text");
+        let warnings: Vec<_> = output.warnings.iter().map(|w| (w.message.as_str(), w.line)).collect();
+        assert_eq!(warnings, [("config for main is deprecated", Some(4)), ("`&warning` directive", Some(5))]);
+    }
+
+    #[test]
+    fn source_map() {
+        let files = [("main.c", "int a;\n//&include <inc.h>\nint b;"), ("inc.h", "int x;")];
+        let mut fetcher = FlatFetcher::new(&files);
+        let options = Options { line_markers: LineMarkers::C, ..Options::default() };
+        let (_, deps) = generate_dependencies("main.c", &mut fetcher, &CommentParser::from("//"), &options).unwrap();
+        let (source, map) = build_file_with_map(&deps, &options).unwrap();
+
+        assert_eq!(source.lines().count(), map.lines.len());
+        assert_eq!(map.lines, vec![
+            None,
+            Some(Location::new("main.c", 1)),
+            None,
+            Some(Location::new("inc.h", 1)),
+            None,
+            Some(Location::new("main.c", 3)),
+        ]);
+        assert_eq!(map.locate(4).unwrap().include_stack, &[Location::new("main.c", 2)]);
+        assert_eq!(map.locate(6).unwrap().include_stack, &[]);
+    }
+
+    #[test]
+    fn error_locations() {
+        let files = [
+            ("main.txt", "text\n//&include <inner.txt>"),
+            ("inner.txt", "//&include <missing.txt>"),
+        ];
+        let error = build(&files, &Options::default()).unwrap_err();
+        assert_eq!(error.to_string(), "inner.txt:1: file not found <missing.txt>\n    included from main.txt:2");
+
+        let files = [("main.txt", "//&include <inner.txt>"), ("inner.txt", "\n//&if 1 +")];
+        let error = build(&files, &Options::default()).unwrap_err();
+        assert_eq!(error.to_string(), "inner.txt:2: conditional is never closed with `&endif`\n    included from main.txt:1");
+
+        // a file which exists but isn't text
+        let dir = std::env::temp_dir().join(format!("preproc-binary-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.txt"), "text\n//&include \"bin.dat\"").unwrap();
+        std::fs::write(dir.join("bin.dat"), b"\xff\xfe").unwrap();
+        let main = dir.join("main.txt").to_str().unwrap().to_owned();
+        let error = generate_dependencies(&main, &mut FilesystemFetcher::new(), &CommentParser::from("//"), &Options::default()).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(error.file.as_deref().is_some_and(|file| file.ends_with("main.txt")));
+        assert_eq!(error.line, Some(2));
+        assert!(error.message.starts_with("file could not be read as text"));
+    }
+
+    #[test]
+    fn local_includes() {
+        let mut fetcher = MemoryFetcher::new();
+        fetcher.add_file("src/main.c", "//&include \"lib/util.h\"\n//&include <config.h>\nmain");
+        fetcher.add_file("src/lib/util.h", "//&include \"../config.h\"\nutil");
+        fetcher.add_file("src/config.h", "local config");
+        fetcher.add_file("include/config.h", "config");
+        fetcher.add_path("include");
+        let parser = CommentParser::from("//");
+        let (_, deps) = generate_dependencies("src/main.c", &mut fetcher, &parser, &Options::default()).unwrap();
+        assert_eq!(deps.keys().collect::<Vec<_>>(), ["src/main.c", "src/lib/util.h", "src/config.h", "include/config.h"]);
+        assert_eq!(build_file(&deps, &Options::default()).unwrap(), "local config\nutil\nconfig\nmain");
+    }
+
+    #[test]
+    fn overlay_builds() {
+        use normpath::PathExt;
+
+        let mut generated = MemoryFetcher::new();
+        generated.add_file("main.c", "//&include <config.h>\n//&include <lib/main.c>\nmain");
+        generated.add_file("config.h", "generated config");
+        generated.add_file("lib/a.h", "GEN");
+        let mut files = MemoryFetcher::new();
+        files.add_file("config.h", "config");
+        let mut vendored = MemoryFetcher::new();
+        vendored.add_file("lib/main.c", "//&include \"*.h\"\n//&include \"a.h\"");
+        vendored.add_file("lib/a.h", "VENDOR");
+        let mut fetcher = OverlayFetcher::new();
+        fetcher.add_layer("gen", generated);
+        fetcher.add_layer("", files);
+        fetcher.add_layer("vendor", vendored);
+
+        // the seed comes from the first layer having it, local includes and globs stay in their layer
+        let options = Options { policy: IncludePolicy::EveryInclude, ..Options::default() };
+        let (_, deps) = generate_dependencies("main.c", &mut fetcher, &CommentParser::from("//"), &options).unwrap();
+        assert_eq!(deps.keys().collect::<Vec<_>>(), ["gen:main.c", "gen:config.h", "vendor:lib/main.c", "vendor:lib/a.h"]);
+        assert_eq!(build_file(&deps, &options).unwrap(), "generated config\nVENDOR\nVENDOR\nmain");
+
+        // dependency files list the paths on disk of the files from any layer
+        let mut generated = MemoryFetcher::new();
+        generated.add_file("main.c", "//&include <testest/d.txt>");
+        generated.add_file("b.txt", "generated b");
+        let mut filesystem = FilesystemFetcher::new();
+        filesystem.add_path("test");
+        let mut fetcher = OverlayFetcher::new();
+        fetcher.add_layer("gen", generated);
+        fetcher.add_layer("disk", filesystem);
+        let (_, deps) = generate_dependencies("main.c", &mut fetcher, &CommentParser::from("//"), &options).unwrap();
+        let test = std::env::current_dir().unwrap().join("test").normalize().unwrap().as_path().to_str().unwrap().to_owned();
+        let disk = [format!("{}/testest/d.txt", test), format!("{}/testest/a.txt", test)];
+        let keys = ["gen:main.c".to_owned(), format!("disk:{}", disk[0]), format!("disk:{}", disk[1]), "gen:b.txt".to_owned()];
+        assert_eq!(deps.keys().collect::<Vec<_>>(), keys.iter().collect::<Vec<_>>());
+        assert_eq!(create_depfile("main.i", None, &deps), format!("main.i: {} {}", disk[0], disk[1]));
+
+        let mut generated = MemoryFetcher::new();
+        generated.add_file("main.c", "main");
+        let mut fetcher = OverlayFetcher::new();
+        fetcher.add_layer("gen", generated);
+        let (_, deps) = generate_dependencies("main.c", &mut fetcher, &CommentParser::from("//"), &options).unwrap();
+        assert_eq!(build_file(&deps, &options).unwrap(), "main");
+    }
+
+    #[test]
+    fn discovery_order() {
+        let files = [
+            ("main.txt", "//&include <z.txt>\n//&include <b.txt>\n//&include <m.txt>"),
+            ("z.txt", "//&include <y.txt>"),
+            ("y.txt", ""),
+            ("b.txt", "//&include <a.txt>"),
+            ("a.txt", ""),
+            ("m.txt", ""),
+        ];
+        let mut fetcher = FlatFetcher::new(&files);
+        let (_, deps) = generate_dependencies("main.txt", &mut fetcher, &CommentParser::from("//"), &Options::default()).unwrap();
+        assert_eq!(create_depfile("main.i", None, &deps), "main.i: main.txt z.txt y.txt b.txt a.txt m.txt");
+    }
+}
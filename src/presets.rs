@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::process::{ParseLine, CommentParser, BlockCommentParser};
+
+/// Chooses the parser used for a file from its resolved name
+pub trait SelectParser {
+    fn parser_for(&self, name: &str) -> &dyn ParseLine;
+}
+
+/// A single parser is used for every file
+impl<T: ParseLine> SelectParser for T {
+    fn parser_for(&self, _name: &str) -> &dyn ParseLine {
+        self
+    }
+}
+
+impl SelectParser for dyn ParseLine {
+    fn parser_for(&self, _name: &str) -> &dyn ParseLine {
+        self
+    }
+}
+
+/// How comments are written in a language
+#[derive(Debug, Clone, Copy)]
+enum CommentStyle {
+    Line(&'static str),
+    Block(&'static str, &'static str),
+}
+
+const BUILTIN: &[(&[&str], CommentStyle)] = &[
+    (&["c", "h", "cc", "cpp", "hpp", "cs", "java", "js", "ts", "go", "rs", "swift", "kt"], CommentStyle::Line("//")),
+    (&["py", "sh", "bash", "zsh", "rb", "pl", "r", "yml", "yaml", "toml", "mk", "cmake"], CommentStyle::Line("#")),
+    (&["sql", "lua", "hs", "ada"], CommentStyle::Line("--")),
+    (&["tex", "sty", "erl"], CommentStyle::Line("%")),
+    (&["ini", "asm", "lisp", "el", "clj"], CommentStyle::Line(";")),
+    (&["html", "htm", "xml", "svg", "md"], CommentStyle::Block("<!--", "-->")),
+    (&["css"], CommentStyle::Block("/*", "*/")),
+];
+
+/// Registry of parsers by file extension, with a fallback for unknown extensions
+pub struct LanguagePresets {
+    parsers: HashMap<String, Box<dyn ParseLine>>,
+    default: Box<dyn ParseLine>,
+}
+
+impl LanguagePresets {
+    /// An empty registry, using `default` for every file
+    pub fn new(default: Box<dyn ParseLine>) -> LanguagePresets {
+        LanguagePresets { parsers: HashMap::new(), default }
+    }
+
+    /// The builtin presets, e.g. `#` for `.py` and `.sh`, `--` for `.sql` and `<!-- -->` for `.html`,
+    /// falling back to `//`. Every parser uses `sigil` and the `(alias, keyword)` pairs in `aliases`.
+    pub fn builtin(sigil: &str, aliases: &[(String, String)]) -> LanguagePresets {
+        let make_parser = |style| -> Box<dyn ParseLine> {
+            match style {
+                CommentStyle::Line(comment) => {
+                    let mut parser = CommentParser::with_sigil(comment, sigil);
+                    aliases.iter().for_each(|(alias, keyword)| parser.add_alias(alias, keyword));
+                    Box::new(parser)
+                }
+                CommentStyle::Block(open, close) => {
+                    let mut parser = BlockCommentParser::with_sigil(open, close, sigil);
+                    aliases.iter().for_each(|(alias, keyword)| parser.add_alias(alias, keyword));
+                    Box::new(parser)
+                }
+            }
+        };
+
+        let mut presets = LanguagePresets::new(make_parser(CommentStyle::Line("//")));
+        for (extensions, style) in BUILTIN {
+            for extension in *extensions {
+                presets.add(extension, make_parser(*style));
+            }
+        }
+        presets
+    }
+
+    /// Uses `parser` for files ending in `.<extension>`, replacing any previous parser
+    pub fn add(&mut self, extension: &str, parser: Box<dyn ParseLine>) {
+        self.parsers.insert(extension.to_lowercase(), parser);
+    }
+}
+
+impl SelectParser for LanguagePresets {
+    fn parser_for(&self, name: &str) -> &dyn ParseLine {
+        Path::new(name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| self.parsers.get(&e.to_lowercase()))
+            .unwrap_or(&self.default)
+            .as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::{PreprocCommand, IncludeOptions};
+
+    #[test]
+    fn select_by_extension() {
+        let presets = LanguagePresets::builtin("&", &[("import".into(), "include".into())]);
+        let include = Some(Ok(PreprocCommand::Include("a".into(), IncludeOptions::default())));
+
+        assert_eq!(presets.parser_for("/src/main.c").parse_line("//&include <a>"), include);
+        assert_eq!(presets.parser_for("/src/run.SH").parse_line("#&import <a>"), include);
+        assert_eq!(presets.parser_for("/src/run.sh").parse_line("//&include <a>"), None);
+        assert_eq!(presets.parser_for("/db/q.sql").parse_line("--&include <a>"), include);
+        assert_eq!(presets.parser_for("/doc.tex").parse_line("%&include <a>"), include);
+        assert_eq!(presets.parser_for("/index.html").parse_line("<!-- &include <a> -->"), include);
+        assert_eq!(presets.parser_for("/notes.txt").parse_line("//&include <a>"), include);
+        assert_eq!(presets.parser_for("/Makefile").parse_line("//&include <a>"), include);
+    }
+}
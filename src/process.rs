@@ -1,3 +1,11 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+use crate::embed::Encoding;
+use crate::error::{PreprocessError, ErrorKind};
+use crate::symbols::{Symbols, is_identifier};
+
 type Lines<'a> = Vec<&'a str>;
 
 pub struct Source<'a>(Lines<'a>);
@@ -9,68 +17,487 @@ impl<'a> Source<'a>
         Source (source.lines().collect())
     }
 
-    pub fn process<T>(&self, parser: &T) -> Result<PreprocessPoints<'a>, String>
+    /// Parses every directive, the errors carry the 1-based line but no file name
+    pub fn process<T>(&self, parser: &T) -> Result<PreprocessPoints, PreprocessError>
     where
-        T: ParseLine
+        T: ParseLine + ?Sized
     {
         let mut pp = PreprocessPoints::new();
+        // line of each open conditional and whether its `&else` has been seen
+        let mut open_conditionals: Vec<(usize, bool)> = Vec::new();
+        let mut open_regions: Vec<usize> = Vec::new();
+        let error = |i: usize, message: &str| PreprocessError::new(ErrorKind::Parse, message).at_line(i + 1, self.0[i]);
+
         for (i, line) in self.0.iter().enumerate() {
             if let Some(parsed_line) = parser.parse_line(line) {
                 match parsed_line {
-                    Ok(com) => { pp.0.push((i, com)); },
-                    Err(s) => { return Err(format!("line {}: {}", i, s)); }
+                    Ok(com) => {
+                        match com {
+                            PreprocCommand::If(_) | PreprocCommand::Ifdef(_) | PreprocCommand::Ifndef(_) => {
+                                open_conditionals.push((i, false));
+                            }
+                            PreprocCommand::Else => match open_conditionals.last_mut() {
+                                Some((_, seen_else @ false)) => { *seen_else = true; }
+                                Some(_) => { return Err(error(i, "duplicate `&else`")); }
+                                None => { return Err(error(i, "`&else` without matching `&if`")); }
+                            }
+                            PreprocCommand::Endif if open_conditionals.pop().is_none() => {
+                                return Err(error(i, "`&endif` without matching `&if`"));
+                            }
+                            PreprocCommand::Region(_) => open_regions.push(i),
+                            PreprocCommand::EndRegion if open_regions.pop().is_none() => {
+                                return Err(error(i, "`&endregion` without matching `&region`"));
+                            }
+                            _ => {}
+                        }
+                        pp.0.push((i, com));
+                    },
+                    Err(s) => { return Err(error(i, &s)); }
                 }
             }
         }
 
+        if let Some((i, _)) = open_conditionals.pop() {
+            return Err(error(i, "conditional is never closed with `&endif`"));
+        }
+        if let Some(i) = open_regions.pop() {
+            return Err(error(i, "region is never closed with `&endregion`"));
+        }
+
         Ok(pp)
     }
 }
 
 
 
-#[derive(Debug)]
-pub enum PreprocCommand<'a> {
-    Include(&'a str),
-    IncludeLocal(&'a str),
+/// The part of a file an include pulls in
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Selection {
+    /// `lines 10-40`, 1-based and inclusive
+    Lines(usize, usize),
+    /// `region name`, the lines between `&region name` and its `&endregion`
+    Region(String),
+}
+
+impl Selection {
+    /// The 0-based indices of the selected lines, given the file's directives and line count
+    pub(crate) fn range(&self, directives: &[(usize, PreprocCommand)], line_count: usize) -> Result<Range<usize>, String> {
+        match self {
+            Selection::Lines(first, last) if *last > line_count => {
+                Err(format!("can't select lines {}-{} from a file with {} lines", first, last, line_count))
+            }
+            Selection::Lines(first, last) => Ok(first - 1..*last),
+            Selection::Region(name) => {
+                let mut start = None;
+                let mut depth = 0;
+                for (i, command) in directives {
+                    match (command, start) {
+                        (PreprocCommand::Region(n), None) if n == name => start = Some(i + 1),
+                        (PreprocCommand::Region(_), Some(_)) => depth += 1,
+                        (PreprocCommand::EndRegion, Some(s)) if depth == 0 => return Ok(s..*i),
+                        (PreprocCommand::EndRegion, Some(_)) => depth -= 1,
+                        _ => {}
+                    }
+                }
+                Err(format!("region `{}` not found", name))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selection::Lines(first, last) => write!(f, "lines {}-{}", first, last),
+            Selection::Region(name) => write!(f, "region {}", name),
+        }
+    }
+}
+
+/// Clauses following the file name of an include, e.g. `//&include <snippet.py> indent`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IncludeOptions {
+    /// `indent` or `noindent`, whether the included lines get the indentation of the directive.
+    /// `None` leaves it to [`Options::indent_includes`](crate::Options::indent_includes).
+    pub indent: Option<bool>,
+    /// Written as `&include?`, the include is skipped if the file doesn't exist
+    pub optional: bool,
+    /// Written as `&include_raw` or `&verbatim`, the file is pasted without processing it
+    pub raw: bool,
+    /// `lines 10-40` or `region name`, only that part of the file is included
+    pub selection: Option<Selection>,
+    /// Written as `&embed <file> as <encoding>`, the bytes of the file are pasted as a literal
+    pub embed: Option<Encoding>,
+    /// `name=users` or `title="Users table"`, bound as symbols while the file is expanded
+    pub args: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreprocCommand {
+    Include(String, IncludeOptions),
+    IncludeLocal(String, IncludeOptions),
+    If(String),
+    Ifdef(String),
+    Ifndef(String),
+    Else,
+    Endif,
+    Define(String, String),
+    Undef(String),
+    Once,
+    Region(String),
+    EndRegion,
+    Error(String),
+    Warning(String),
 }
 
 pub trait ParseLine {
-    fn parse_line<'a>(&self, line: &'a str) -> Option<Result<PreprocCommand<'a>, String>>;
+    fn parse_line(&self, line: &str) -> Option<Result<PreprocCommand, String>>;
+
+    /// The delimiters opening and closing a comment, e.g. `("//", "")` or `("<!--", "-->")`
+    fn comment_delimiters(&self) -> Option<(&str, &str)> {
+        None
+    }
 }
 
-pub struct CommentParser(String);
+/// The directive sigil used when none is given
+const DEFAULT_SIGIL: &str = "&";
+
+/// Recognises directives as `<comment><sigil><keyword> ...`, e.g. `//&include <file>`.
+///
+/// Whitespace may precede the comment, the sigil must follow it directly. The directive may be
+/// followed by commentary starting with whitespace and the comment again, e.g.
+/// `//&endif // DEBUG`; quoted strings and the text of `&define`, `&error` and `&warning` are
+/// never cut short.
+pub struct CommentParser {
+    comment: String,
+    syntax: DirectiveSyntax,
+}
+
+impl CommentParser {
+    pub fn new(comment: &str) -> CommentParser {
+        CommentParser::with_sigil(comment, DEFAULT_SIGIL)
+    }
+
+    pub fn with_sigil(comment: &str, sigil: &str) -> CommentParser {
+        CommentParser { comment: comment.to_owned(), syntax: DirectiveSyntax::new(sigil) }
+    }
+
+    /// Makes `alias` an alternative spelling of the directive `keyword`, e.g. `import` for `include`
+    pub fn add_alias(&mut self, alias: &str, keyword: &str) {
+        self.syntax.add_alias(alias, keyword);
+    }
+}
 
 impl From<&str> for CommentParser {
     fn from(s: &str) -> Self {
-        CommentParser(s.to_owned())
+        CommentParser::new(s)
     }
 }
 
 impl From<String> for CommentParser {
     fn from(s: String) -> Self {
-        CommentParser(s)
+        CommentParser::new(&s)
     }
 }
 
 impl ParseLine for CommentParser {
-    fn parse_line<'a>(&self, line: &'a str) -> Option<Result<PreprocCommand<'a>, String>> 
+    fn parse_line(&self, line: &str) -> Option<Result<PreprocCommand, String>> 
     {
-        if let Some(rem) = line.strip_prefix(self.0.as_str()).and_then(|r| r.strip_prefix("&")) {
-            if let Some(com) = rem.strip_prefix("include") { 
-                if let Some(filename) = com.trim().strip_prefix("<").and_then(|r| r.strip_suffix(">")) {
-                    Some(Ok(PreprocCommand::Include(filename)))
-                } else if let Some(filename) = com.trim().strip_prefix("\"").and_then(|r| r.strip_suffix("\"")) {
-                    Some(Ok(PreprocCommand::IncludeLocal(filename)))
-                } else {
-                    Some(Err(format!("invalid include statement `{}`", rem)))
+        let rem = line.trim_start().strip_prefix(self.comment.as_str())?;
+        self.syntax.parse(rem, Some(&self.comment))
+    }
+
+    fn comment_delimiters(&self) -> Option<(&str, &str)> {
+        Some((&self.comment, ""))
+    }
+}
+
+/// Recognises directives wrapped in a block comment on a single line,
+/// as `<open> <sigil><keyword> ... <close>`, e.g. `<!-- &include "header.html" -->`.
+///
+/// Whitespace may surround the comment, and another block comment may follow it,
+/// e.g. `<!-- &endif --> <!-- DEBUG -->`.
+pub struct BlockCommentParser {
+    open: String,
+    close: String,
+    syntax: DirectiveSyntax,
+}
+
+impl BlockCommentParser {
+    pub fn new(open: &str, close: &str) -> BlockCommentParser {
+        BlockCommentParser::with_sigil(open, close, DEFAULT_SIGIL)
+    }
+
+    pub fn with_sigil(open: &str, close: &str, sigil: &str) -> BlockCommentParser {
+        BlockCommentParser { open: open.to_owned(), close: close.to_owned(), syntax: DirectiveSyntax::new(sigil) }
+    }
+
+    /// Makes `alias` an alternative spelling of the directive `keyword`, e.g. `import` for `include`
+    pub fn add_alias(&mut self, alias: &str, keyword: &str) {
+        self.syntax.add_alias(alias, keyword);
+    }
+}
+
+impl ParseLine for BlockCommentParser {
+    fn parse_line(&self, line: &str) -> Option<Result<PreprocCommand, String>> {
+        let rem = line.trim().strip_prefix(self.open.as_str())?;
+        let (inner, after) = rem.split_once(self.close.as_str())?;
+        let after = after.trim_start();
+        let commentary = after.is_empty() || (after.starts_with(self.open.as_str()) && after.ends_with(self.close.as_str()));
+        if commentary { self.syntax.parse(inner.trim(), None) } else { None }
+    }
+
+    fn comment_delimiters(&self) -> Option<(&str, &str)> {
+        Some((&self.open, &self.close))
+    }
+}
+
+/// Cuts `text` before the first `comment` which is preceded by whitespace and not inside quotes
+fn strip_trailing_comment<'a>(text: &'a str, comment: &str) -> &'a str {
+    let mut quoted = false;
+    let mut after_space = false;
+    for (i, c) in text.char_indices() {
+        if !quoted && after_space && text[i..].starts_with(comment) {
+            return &text[..i];
+        }
+        if c == '"' {
+            quoted = !quoted;
+        }
+        after_space = c.is_whitespace();
+    }
+    text
+}
+
+/// Splits the clauses of an include at whitespace outside of quotes, dropping the quotes.
+/// Returns `None` for an unterminated string.
+fn split_clauses(text: &str) -> Option<Vec<String>> {
+    let mut clauses = Vec::new();
+    let mut clause: Option<String> = None;
+    let mut quoted = false;
+    for c in text.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                clause.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => clauses.extend(clause.take()),
+            c => clause.get_or_insert_with(String::new).push(c),
+        }
+    }
+    clauses.extend(clause);
+    (!quoted).then_some(clauses)
+}
+
+/// The sigil and keyword aliases shared by the parsers
+struct DirectiveSyntax {
+    sigil: String,
+    aliases: HashMap<String, String>,
+}
+
+impl DirectiveSyntax {
+    fn new(sigil: &str) -> DirectiveSyntax {
+        DirectiveSyntax { sigil: sigil.to_owned(), aliases: HashMap::new() }
+    }
+
+    fn add_alias(&mut self, alias: &str, keyword: &str) {
+        self.aliases.insert(alias.to_owned(), keyword.to_owned());
+    }
+
+    /// Parses `text` if it starts with the sigil. Trailing commentary starting with whitespace
+    /// and `comment` is cut, except from the free text of `&define`, `&error` and `&warning`,
+    /// which may contain the comment itself, e.g. `#&define COLOR #fff`.
+    fn parse(&self, text: &str, comment: Option<&str>) -> Option<Result<PreprocCommand, String>> {
+        let rem = text.strip_prefix(self.sigil.as_str())?;
+        let keyword_end = rem.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rem.len());
+        let keyword = &rem[..keyword_end];
+        let keyword = self.aliases.get(keyword).map_or(keyword, |k| k.as_str());
+        let rem = match comment {
+            Some(comment) if !matches!(keyword, "define" | "error" | "warning") => strip_trailing_comment(rem, comment),
+            _ => rem,
+        };
+        let rem = rem.trim_end();
+        let args = &rem[keyword_end..];
+        // a `?` directly after the keyword marks it optional, e.g. `&include?`
+        match args.strip_prefix('?') {
+            Some(args) => Some(parse_command(rem, &format!("{}?", keyword), args.trim())),
+            None => Some(parse_command(rem, keyword, args.trim())),
+        }
+    }
+}
+
+/// Parses a directive from its keyword and arguments, `rem` being the full directive text
+fn parse_command(rem: &str, keyword: &str, args: &str) -> Result<PreprocCommand, String> {
+    match keyword {
+        "include" | "include?" | "include_raw" | "include_raw?" | "verbatim" | "verbatim?" | "embed" | "embed?" => {
+            let close = match args.chars().next() {
+                Some('<') => '>',
+                Some('"') => '"',
+                _ => return Err(format!("invalid include statement `{}`", rem)),
+            };
+            let end = args[1..].find(close).ok_or_else(|| format!("invalid include statement `{}`", rem))? + 1;
+            let (filename, clauses) = (&args[1..end], &args[end + 1..]);
+            if !clauses.is_empty() && !clauses.starts_with(char::is_whitespace) {
+                return Err(format!("invalid include statement `{}`", rem));
+            }
+
+            let mut options = IncludeOptions {
+                optional: keyword.ends_with('?'),
+                raw: matches!(keyword.trim_end_matches('?'), "include_raw" | "verbatim"),
+                ..IncludeOptions::default()
+            };
+            let mut clauses = split_clauses(clauses).ok_or_else(|| format!("unterminated string in `{}`", rem))?.into_iter();
+            while let Some(clause) = clauses.next() {
+                match clause.as_str() {
+                    "indent" => options.indent = Some(true),
+                    "noindent" => options.indent = Some(false),
+                    "as" if keyword.starts_with("embed") && options.embed.is_none() => {
+                        let name = clauses.next().unwrap_or_default();
+                        options.embed = Some(Encoding::from_name(&name).ok_or_else(|| {
+                            format!("unknown encoding `{}` in `{}`, expected `base64`, `hex`, `c-array`, `rust-bytes` or `string-literal`", name, rem)
+                        })?);
+                    }
+                    "lines" | "region" if keyword.starts_with("embed") => {
+                        return Err(format!("can't select part of an embedded file in `{}`", rem));
+                    }
+                    "lines" | "region" if options.selection.is_some() => {
+                        return Err(format!("only one of `lines` and `region` may be given in `{}`", rem));
+                    }
+                    "lines" => {
+                        let range = clauses.next().unwrap_or_default();
+                        let (first, last) = range.split_once('-').unwrap_or((&range, &range));
+                        match (first.parse::<usize>(), last.parse::<usize>()) {
+                            (Ok(first), Ok(last)) if 0 < first && first <= last => {
+                                options.selection = Some(Selection::Lines(first, last));
+                            }
+                            _ => return Err(format!("expected a line range as `lines <first>-<last>` in `{}`", rem)),
+                        }
+                    }
+                    "region" if options.raw => {
+                        return Err(format!("regions can't be selected from raw includes in `{}`", rem));
+                    }
+                    "region" => match clauses.next() {
+                        Some(name) => options.selection = Some(Selection::Region(name)),
+                        None => return Err(format!("expected a region name after `region` in `{}`", rem)),
+                    },
+                    _ => match clause.split_once('=') {
+                        Some((name, value)) if is_identifier(name) => options.args.push((name.to_owned(), value.to_owned())),
+                        _ => return Err(format!("unknown include option `{}` in `{}`", clause, rem)),
+                    },
                 }
+            }
+
+            if keyword.starts_with("embed") && options.embed.is_none() {
+                return Err(format!("expected an encoding as `as <encoding>` in `{}`", rem));
+            }
+
+            if close == '>' {
+                Ok(PreprocCommand::Include(filename.to_owned(), options))
+            } else {
+                Ok(PreprocCommand::IncludeLocal(filename.to_owned(), options))
+            }
+        }
+        "if" if !args.is_empty() => Ok(PreprocCommand::If(args.to_owned())),
+        "ifdef" | "ifndef" => {
+            if !is_identifier(args) {
+                Err(format!("expected a single symbol name in `{}`", rem))
+            } else if keyword == "ifdef" {
+                Ok(PreprocCommand::Ifdef(args.to_owned()))
             } else {
-                Some(Err(format!("invalid preproc statement `{}`", rem)))
+                Ok(PreprocCommand::Ifndef(args.to_owned()))
             }
-        } else {
-            None
         }
+        "define" => {
+            let name_end = args.find(char::is_whitespace).unwrap_or(args.len());
+            let (name, value) = args.split_at(name_end);
+            if is_identifier(name) {
+                Ok(PreprocCommand::Define(name.to_owned(), value.trim().to_owned()))
+            } else {
+                Err(format!("invalid symbol name in `{}`", rem))
+            }
+        }
+        "undef" if is_identifier(args) => Ok(PreprocCommand::Undef(args.to_owned())),
+        "once" if args.is_empty() => Ok(PreprocCommand::Once),
+        "region" if !args.is_empty() && !args.contains(char::is_whitespace) => Ok(PreprocCommand::Region(args.to_owned())),
+        "endregion" => Ok(PreprocCommand::EndRegion),
+        "error" => Ok(PreprocCommand::Error(args.to_owned())),
+        "warning" => Ok(PreprocCommand::Warning(args.to_owned())),
+        "else" if args.is_empty() => Ok(PreprocCommand::Else),
+        "endif" if args.is_empty() => Ok(PreprocCommand::Endif),
+        _ => Err(format!("invalid preproc statement `{}`", rem)),
+    }
+}
+
+/// Applies `command` to `symbols` if it is a definition, returning `false` for any other command.
+/// Values are expanded at the point of definition.
+pub(crate) fn apply_definition(command: &PreprocCommand, symbols: &mut Symbols) -> bool {
+    match command {
+        PreprocCommand::Define(name, value) => {
+            let value = symbols.expand(value).into_owned();
+            symbols.define(name, &value);
+        }
+        PreprocCommand::Undef(name) => { symbols.undefine(name); }
+        _ => return false,
+    }
+    true
+}
+
+/// Expands symbols in the values of include arguments
+pub(crate) fn expand_arguments(args: &[(String, String)], symbols: &Symbols) -> Vec<(String, String)> {
+    args.iter().map(|(name, value)| (name.clone(), symbols.expand(value).into_owned())).collect()
+}
+
+/// Tracks nested conditional blocks while walking the directives of a file
+pub(crate) struct Conditionals(Vec<Branch>);
+
+struct Branch {
+    /// whether the enclosing block is active
+    parent: bool,
+    /// whether any branch of this block has been taken
+    taken: bool,
+    active: bool,
+}
+
+impl Conditionals {
+    pub fn new() -> Conditionals {
+        Conditionals(Vec::new())
+    }
+
+    /// The conditionals in effect at line `start`, evaluating the conditionals of `directives`
+    /// before it, so that a selection may start inside a block. Errors come with their line.
+    pub fn at_line(directives: &[(usize, PreprocCommand)], start: usize, symbols: &Symbols) -> Result<Conditionals, (usize, String)> {
+        let mut conditionals = Conditionals::new();
+        for (i, command) in directives.iter().take_while(|(i, _)| *i < start) {
+            conditionals.apply(command, symbols).map_err(|e| (*i, e))?;
+        }
+        Ok(conditionals)
+    }
+
+    /// Whether lines at the current position should be kept
+    pub fn is_active(&self) -> bool {
+        self.0.last().is_none_or(|b| b.active)
+    }
+
+    /// Applies `command` if it is a conditional, returning `false` for any other command
+    pub fn apply(&mut self, command: &PreprocCommand, symbols: &Symbols) -> Result<bool, String> {
+        let parent = self.is_active();
+        let condition = match command {
+            PreprocCommand::If(expr) => parent && symbols.evaluate(expr)?,
+            PreprocCommand::Ifdef(name) => parent && symbols.is_defined(name),
+            PreprocCommand::Ifndef(name) => parent && !symbols.is_defined(name),
+            PreprocCommand::Else => {
+                let branch = self.0.last_mut().ok_or("`&else` without matching `&if`")?;
+                branch.active = branch.parent && !branch.taken;
+                branch.taken = true;
+                return Ok(true);
+            }
+            PreprocCommand::Endif => {
+                self.0.pop().ok_or("`&endif` without matching `&if`")?;
+                return Ok(true);
+            }
+            _ => return Ok(false),
+        };
+        self.0.push(Branch { parent, taken: condition, active: condition });
+        Ok(true)
     }
 }
 
@@ -81,25 +508,30 @@ pub enum IncludePoint<'a> {
 }
 
 #[derive(Debug)]
-pub struct PreprocessPoints<'a>(Vec<(usize, PreprocCommand<'a>)>);
+pub struct PreprocessPoints(Vec<(usize, PreprocCommand)>);
 
-impl<'a> PreprocessPoints<'a> {
-    pub fn new() -> PreprocessPoints<'a> {
-        PreprocessPoints { 0: Vec::new() }
+impl PreprocessPoints {
+    pub fn new() -> PreprocessPoints {
+        PreprocessPoints(Vec::new())
     }
-    pub fn get_include_points(&self) -> Vec<IncludePoint> {
+
+    #[allow(dead_code)]
+    pub fn get_include_points(&self) -> Vec<IncludePoint<'_>> {
         let mut include_points = Vec::new();
         for (linenr, command) in &self.0 {
-            include_points.push(
-                match command {
-                    PreprocCommand::Include(filename) => IncludePoint::Global(*linenr, *filename),
-                    PreprocCommand::IncludeLocal(filename) => IncludePoint::Local(*linenr, *filename),
-                }
-            );
+            match command {
+                PreprocCommand::Include(filename, _) => include_points.push(IncludePoint::Global(*linenr, filename)),
+                PreprocCommand::IncludeLocal(filename, _) => include_points.push(IncludePoint::Local(*linenr, filename)),
+                _ => {}
+            }
         }
         include_points
     }
 
+    pub fn into_commands(self) -> Vec<(usize, PreprocCommand)> {
+        self.0
+    }
+
     #[allow(dead_code)]
     pub fn len(&self) -> usize {
         self.0.len()
@@ -154,7 +586,202 @@ if __name__ == \"__main__\":
 
         let source1 = Source::from_str(wrong_file);
         let pp1_error = source1.process::<CommentParser>(&"//".into()).expect_err("file shouldn't parse");
-        assert_eq!(pp1_error, "line 0: invalid preproc statement `wrong <not read>`");
+        assert_eq!(pp1_error.to_string(), "line 1: invalid preproc statement `wrong <not read>`");
+        assert_eq!(pp1_error.kind, ErrorKind::Parse);
+        assert_eq!(pp1_error.directive.as_deref(), Some(wrong_file));
+
+    }
+
+    #[test]
+    fn conditionals() {
+        let file = "//&ifdef DEBUG
+//&include <debug.c>
+//&else
+//&if PLATFORM == \"linux\"
+//&include \"linux.c\"
+//&endif
+//&endif";
+        let pp = Source::from_str(file).process::<CommentParser>(&"//".into()).expect("file is correct");
+        assert_eq!(pp.len(), 7);
+        assert_eq!(pp.get_include_points(), vec![IncludePoint::Global(1, "debug.c"), IncludePoint::Local(4, "linux.c")]);
+
+        let mut symbols = Symbols::new();
+        symbols.define("PLATFORM", "linux");
+        let mut conditionals = Conditionals::new();
+        let mut active = Vec::new();
+        for (i, command) in pp.into_commands() {
+            if !conditionals.apply(&command, &symbols).unwrap() && conditionals.is_active() {
+                active.push(i);
+            }
+        }
+        assert_eq!(active, vec![4]);
+
+        let unclosed = Source::from_str("//&ifndef DEBUG\n//&else\n//&else").process::<CommentParser>(&"//".into());
+        assert_eq!(unclosed.expect_err("duplicate else").to_string(), "line 3: duplicate `&else`");
+        let unclosed = Source::from_str("//&if 1\ntext").process::<CommentParser>(&"//".into());
+        assert_eq!(unclosed.expect_err("missing endif").to_string(), "line 1: conditional is never closed with `&endif`");
+    }
+
+    #[test]
+    fn definitions() {
+        let parser: CommentParser = "//".into();
+        assert_eq!(parser.parse_line("//&define VERSION 1.4.2"), Some(Ok(PreprocCommand::Define("VERSION".into(), "1.4.2".into()))));
+        assert_eq!(parser.parse_line("//&define GREETING  hello world "), Some(Ok(PreprocCommand::Define("GREETING".into(), "hello world".into()))));
+        assert_eq!(parser.parse_line("//&define DEBUG"), Some(Ok(PreprocCommand::Define("DEBUG".into(), "".into()))));
+        assert_eq!(parser.parse_line("//&undef DEBUG"), Some(Ok(PreprocCommand::Undef("DEBUG".into()))));
+        assert_eq!(parser.parse_line("//&error requires FEATURE_X"), Some(Ok(PreprocCommand::Error("requires FEATURE_X".into()))));
+        assert_eq!(parser.parse_line("//&warning  deprecated "), Some(Ok(PreprocCommand::Warning("deprecated".into()))));
+        assert!(matches!(parser.parse_line("//&define 1ST value"), Some(Err(_))));
+        assert!(matches!(parser.parse_line("//&undef A B"), Some(Err(_))));
+    }
+
+    #[test]
+    fn custom_sigil_and_aliases() {
+        let mut parser = CommentParser::with_sigil("#", "!");
+        parser.add_alias("import", "include");
+        parser.add_alias("use", "include");
+
+        assert_eq!(parser.parse_line("#!include <a.py>"), Some(Ok(PreprocCommand::Include("a.py".into(), IncludeOptions::default()))));
+        assert_eq!(parser.parse_line("#!import \"b.py\""), Some(Ok(PreprocCommand::IncludeLocal("b.py".into(), IncludeOptions::default()))));
+        assert_eq!(parser.parse_line("#!use <c.py>"), Some(Ok(PreprocCommand::Include("c.py".into(), IncludeOptions::default()))));
+        assert_eq!(parser.parse_line("#&include <a.py>"), None);
+        assert_eq!(parser.parse_line("#!import a.py"), Some(Err("invalid include statement `import a.py`".into())));
+
+        let parser = CommentParser::with_sigil("//", "#!");
+        assert_eq!(parser.parse_line("//#!ifdef A"), Some(Ok(PreprocCommand::Ifdef("A".into()))));
+    }
+
+    /// The accepted directive syntax of `CommentParser`
+    #[test]
+    fn grammar() {
+        let parser: CommentParser = "//".into();
+        let include = || Some(Ok(PreprocCommand::Include("x".into(), IncludeOptions::default())));
+
+        // whitespace before the comment and at the end
+        assert_eq!(parser.parse_line("//&include <x>"), include());
+        assert_eq!(parser.parse_line("    //&include <x>"), include());
+        assert_eq!(parser.parse_line("\t//&include <x>  "), include());
+        assert_eq!(parser.parse_line("//&include   <x>\t"), include());
+
+        // trailing commentary starts with whitespace and the comment
+        assert_eq!(parser.parse_line("//&include <x> // why"), include());
+        assert_eq!(parser.parse_line("//&include <x>// why"), Some(Err("invalid include statement `include <x>// why`".into())));
+        assert_eq!(parser.parse_line("//&endif // DEBUG"), Some(Ok(PreprocCommand::Endif)));
+        assert_eq!(parser.parse_line("//&define URL http://host // docs"), Some(Ok(PreprocCommand::Define("URL".into(), "http://host // docs".into()))));
+        assert_eq!(parser.parse_line("//&error needs // and \\ "), Some(Ok(PreprocCommand::Error("needs // and \\".into()))));
+        let hash: CommentParser = "#".into();
+        assert_eq!(hash.parse_line("#&define COLOR #fff"), Some(Ok(PreprocCommand::Define("COLOR".into(), "#fff".into()))));
+        assert_eq!(hash.parse_line("#&endif # COLOR"), Some(Ok(PreprocCommand::Endif)));
+        let dashes: CommentParser = "--".into();
+        assert_eq!(dashes.parse_line("--&define SEP --"), Some(Ok(PreprocCommand::Define("SEP".into(), "--".into()))));
+        assert_eq!(parser.parse_line("//&if NAME == \"a // b\" // c"), Some(Ok(PreprocCommand::If("NAME == \"a // b\"".into()))));
+
+        // anything else is not a directive
+        assert_eq!(parser.parse_line("x //&include <x>"), None);
+        assert_eq!(parser.parse_line("/ /&include <x>"), None);
+        assert_eq!(parser.parse_line("// include <x>"), None);
+        assert_eq!(parser.parse_line("// &include <x>"), None);
+        assert_eq!(parser.parse_line("// &x is the address of x"), None);
+    }
+
+    #[test]
+    fn include_options() {
+        let parser: CommentParser = "#".into();
+        let indent = IncludeOptions { indent: Some(true), ..IncludeOptions::default() };
+        assert_eq!(parser.parse_line("    #&include <a.py> indent"), Some(Ok(PreprocCommand::Include("a.py".into(), indent))));
+        assert_eq!(
+            parser.parse_line("#&include \"b.py\"  noindent "),
+            Some(Ok(PreprocCommand::IncludeLocal("b.py".into(), IncludeOptions { indent: Some(false), ..IncludeOptions::default() }))),
+        );
+        let optional = IncludeOptions { optional: true, ..IncludeOptions::default() };
+        assert_eq!(parser.parse_line("#&include? \"local.py\""), Some(Ok(PreprocCommand::IncludeLocal("local.py".into(), optional))));
+        assert!(matches!(parser.parse_line("#&include ? <a.py>"), Some(Err(_))));
+        let raw = IncludeOptions { raw: true, ..IncludeOptions::default() };
+        assert_eq!(parser.parse_line("#&include_raw <doc.md>"), Some(Ok(PreprocCommand::Include("doc.md".into(), raw.clone()))));
+        assert_eq!(parser.parse_line("#&verbatim <doc.md>"), Some(Ok(PreprocCommand::Include("doc.md".into(), raw))));
+        let optional_raw = IncludeOptions { raw: true, optional: true, ..IncludeOptions::default() };
+        assert_eq!(parser.parse_line("#&verbatim? <doc.md>"), Some(Ok(PreprocCommand::Include("doc.md".into(), optional_raw))));
+        assert!(matches!(parser.parse_line("#&endif?"), Some(Err(_))));
+        assert_eq!(parser.parse_line("#&include <a.py> sideways"), Some(Err("unknown include option `sideways` in `include <a.py> sideways`".into())));
+        assert!(matches!(parser.parse_line("#&include <a.py>indent"), Some(Err(_))));
+        assert!(matches!(parser.parse_line("#&include <a.py"), Some(Err(_))));
+    }
+
+    #[test]
+    fn selections() {
+        let parser: CommentParser = "//".into();
+        let lines = IncludeOptions { selection: Some(Selection::Lines(10, 40)), ..IncludeOptions::default() };
+        assert_eq!(parser.parse_line("//&include <big.c> lines 10-40"), Some(Ok(PreprocCommand::Include("big.c".into(), lines))));
+        let region = IncludeOptions { selection: Some(Selection::Region("setup".into())), indent: Some(true), ..IncludeOptions::default() };
+        assert_eq!(parser.parse_line("//&include \"a.rs\" region setup indent"), Some(Ok(PreprocCommand::IncludeLocal("a.rs".into(), region))));
+        assert_eq!(parser.parse_line("//&include <a.c> lines 7"), Some(Ok(PreprocCommand::Include("a.c".into(), IncludeOptions { selection: Some(Selection::Lines(7, 7)), ..IncludeOptions::default() }))));
+        assert!(matches!(parser.parse_line("//&include <a.c> lines 40-10"), Some(Err(_))));
+        assert!(matches!(parser.parse_line("//&include <a.c> lines 0-1"), Some(Err(_))));
+        assert!(matches!(parser.parse_line("//&include <a.c> region"), Some(Err(_))));
+        assert!(matches!(parser.parse_line("//&include <a.c> region a lines 1-2"), Some(Err(_))));
+        assert!(matches!(parser.parse_line("//&verbatim <a.c> region a"), Some(Err(_))));
+        // `&endregion` may name the region it closes
+        assert_eq!(parser.parse_line("//&endregion setup"), Some(Ok(PreprocCommand::EndRegion)));
+
+        let file = "a\n//&region outer\nb\n//&region inner\nc\n//&endregion\n//&endregion";
+        let directives = Source::from_str(file).process(&parser).unwrap().into_commands();
+        assert_eq!(Selection::Region("outer".into()).range(&directives, 7), Ok(2..6));
+        assert_eq!(Selection::Region("inner".into()).range(&directives, 7), Ok(4..5));
+        assert_eq!(Selection::Region("other".into()).range(&directives, 7), Err("region `other` not found".into()));
+        assert_eq!(Selection::Lines(1, 3).range(&directives, 7), Ok(0..3));
+        assert!(Selection::Lines(1, 8).range(&directives, 7).is_err());
+
+        let unclosed = Source::from_str("//&region a\n//&region b\n//&endregion").process(&parser);
+        assert_eq!(unclosed.expect_err("missing endregion").to_string(), "line 1: region is never closed with `&endregion`");
+    }
+
+    #[test]
+    fn arguments() {
+        let parser: CommentParser = "//".into();
+        let args = vec![("name".to_owned(), "users".to_owned()), ("title".to_owned(), "User table".to_owned()), ("pk".to_owned(), "".to_owned())];
+        assert_eq!(
+            parser.parse_line("//&include <table.sql> name=users title=\"User table\" pk= indent"),
+            Some(Ok(PreprocCommand::Include("table.sql".into(), IncludeOptions { args, indent: Some(true), ..IncludeOptions::default() }))),
+        );
+        assert!(matches!(parser.parse_line("//&include <table.sql> 1st=a"), Some(Err(_))));
+        assert!(matches!(parser.parse_line("//&include <table.sql> title=\"a b"), Some(Err(_))));
+
+        let mut symbols = Symbols::new();
+        symbols.define("name", "outer");
+        symbols.define("TABLE", "users");
+        let args = expand_arguments(&[("name".into(), "TABLE".into()), ("pk".into(), "id".into())], &symbols);
+        let previous = symbols.bind_arguments(&args);
+        assert_eq!((symbols.get("name"), symbols.get("pk")), (Some("users"), Some("id")));
+        // arguments are only substituted in `${}` form
+        assert_eq!(symbols.expand("${name}.${pk} name pk"), "users.id outer pk");
+        symbols.unbind_arguments(previous);
+        assert_eq!((symbols.get("name"), symbols.get("pk")), (Some("outer"), None));
+    }
+
+    #[test]
+    fn embeds() {
+        let parser: CommentParser = "//".into();
+        let embed = IncludeOptions { embed: Some(Encoding::CArray), indent: Some(true), ..IncludeOptions::default() };
+        assert_eq!(parser.parse_line("//&embed \"logo.png\" as c-array indent"), Some(Ok(PreprocCommand::IncludeLocal("logo.png".into(), embed))));
+        assert!(matches!(parser.parse_line("//&embed <logo.png>"), Some(Err(_))));
+        assert!(matches!(parser.parse_line("//&embed <logo.png> as base32"), Some(Err(_))));
+        assert!(matches!(parser.parse_line("//&embed <logo.png> as hex lines 1-2"), Some(Err(_))));
+        assert!(matches!(parser.parse_line("//&include <logo.png> as hex"), Some(Err(_))));
+    }
+
+    #[test]
+    fn block_comments() {
+        let html = BlockCommentParser::new("<!--", "-->");
+        assert_eq!(html.parse_line("<!-- &include \"x.html\" -->"), Some(Ok(PreprocCommand::IncludeLocal("x.html".into(), IncludeOptions::default()))));
+        assert_eq!(html.parse_line("<!--&endif-->"), Some(Ok(PreprocCommand::Endif)));
+        assert_eq!(html.parse_line("<!-- a normal comment -->"), None);
+        assert_eq!(html.parse_line("<!-- &include \"x.html\""), None);
+        assert_eq!(html.parse_line("  <!-- &endif --> <!-- DEBUG -->  "), Some(Ok(PreprocCommand::Endif)));
+        assert_eq!(html.parse_line("<!-- &endif --> <p>"), None);
 
+        let mut css = BlockCommentParser::with_sigil("/*", "*/", "@");
+        css.add_alias("import", "include");
+        assert_eq!(css.parse_line("/* @import <a.css> */"), Some(Ok(PreprocCommand::Include("a.css".into(), IncludeOptions::default()))));
+        assert_eq!(css.parse_line("/* @import a.css */"), Some(Err("invalid include statement `import a.css`".into())));
     }
 }
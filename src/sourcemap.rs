@@ -0,0 +1,218 @@
+use crate::error::Location;
+use crate::json;
+
+/// The base64 alphabet, also used by `&embed`
+pub(crate) const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Where each line of a built file came from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    /// One entry per output line, `None` for generated lines such as line markers
+    pub lines: Vec<Option<Location>>,
+    /// For each output line an index into `include_stacks`
+    pub line_stacks: Vec<usize>,
+    /// The includes which placed lines in the output, outermost first
+    pub include_stacks: Vec<Vec<Location>>,
+}
+
+/// An output line traced back to where it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin<'a> {
+    pub location: &'a Location,
+    /// The includes which led to the file, outermost first
+    pub include_stack: &'a [Location],
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// The original location of the 1-based output `line`
+    pub fn get(&self, line: usize) -> Option<&Location> {
+        self.lines.get(line.checked_sub(1)?)?.as_ref()
+    }
+
+    /// The original location of the 1-based output `line` and the includes which placed it there
+    pub fn locate(&self, line: usize) -> Option<Origin<'_>> {
+        let location = self.get(line)?;
+        let include_stack = self.line_stacks
+            .get(line - 1)
+            .and_then(|s| self.include_stacks.get(*s))
+            .map_or(&[][..], |s| s.as_slice());
+        Some(Origin { location, include_stack })
+    }
+
+    /// The source files in order of first appearance
+    pub fn sources(&self) -> Vec<&str> {
+        let mut sources: Vec<&str> = Vec::new();
+        for location in self.lines.iter().flatten().chain(self.include_stacks.iter().flatten()) {
+            if !sources.contains(&location.file.as_str()) {
+                sources.push(&location.file);
+            }
+        }
+        sources
+    }
+
+    /// Serializes the map as
+    /// `{"sources": [...], "lines": [[source, line, stack], null, ...], "stacks": [[[source, line], ...], ...]}`,
+    /// where `source` indexes `sources`, `stack` indexes `stacks` and `line` is 1-based
+    pub fn to_json(&self) -> String {
+        let sources = self.sources();
+        let source = |file: &String| sources.iter().position(|s| s == file).unwrap();
+        let lines: Vec<_> = self.lines
+            .iter()
+            .enumerate()
+            .map(|(i, l)| match l {
+                Some(Location { file, line }) => {
+                    format!("[{},{},{}]", source(file), line, self.line_stacks.get(i).unwrap_or(&0))
+                }
+                None => "null".to_owned(),
+            })
+            .collect();
+        let stacks: Vec<_> = self.include_stacks
+            .iter()
+            .map(|stack| {
+                let stack: Vec<_> = stack.iter().map(|l| format!("[{},{}]", source(&l.file), l.line)).collect();
+                format!("[{}]", stack.join(","))
+            })
+            .collect();
+        let sources: Vec<_> = sources.iter().map(|s| json::string(s)).collect();
+        format!(
+            "{{\"sources\":[{}],\"lines\":[{}],\"stacks\":[{}]}}",
+            sources.join(","),
+            lines.join(","),
+            stacks.join(","),
+        )
+    }
+
+    /// Reads a map written by [`SourceMap::to_json`]
+    pub fn from_json(text: &str) -> Result<SourceMap, String> {
+        let value = json::parse(text)?;
+        let sources: Vec<&str> = value
+            .get("sources")
+            .and_then(json::Value::as_array)
+            .ok_or("missing `sources`")?
+            .iter()
+            .map(|s| s.as_str().ok_or("`sources` must be strings"))
+            .collect::<Result<_, _>>()?;
+        let location = |v: &[json::Value]| -> Result<Location, String> {
+            match v {
+                [source, line, ..] => {
+                    let file = source.as_usize().and_then(|s| sources.get(s)).ok_or("invalid source index")?;
+                    let line = line.as_usize().ok_or("invalid line number")?;
+                    Ok(Location::new(file, line))
+                }
+                _ => Err("expected `[source, line]`".into()),
+            }
+        };
+
+        let mut map = SourceMap::new();
+        for line in value.get("lines").and_then(json::Value::as_array).ok_or("missing `lines`")? {
+            match line {
+                json::Value::Null => {
+                    map.lines.push(None);
+                    map.line_stacks.push(0);
+                }
+                json::Value::Array(fields) => {
+                    map.lines.push(Some(location(fields)?));
+                    map.line_stacks.push(fields.get(2).and_then(json::Value::as_usize).unwrap_or(0));
+                }
+                _ => return Err("`lines` must be arrays or null".into()),
+            }
+        }
+        // older maps have no stacks
+        for stack in value.get("stacks").and_then(json::Value::as_array).unwrap_or_default() {
+            let stack = stack
+                .as_array()
+                .ok_or("`stacks` must be arrays")?
+                .iter()
+                .map(|l| l.as_array().ok_or_else(|| "expected `[source, line]`".to_owned()).and_then(location))
+                .collect::<Result<_, _>>()?;
+            map.include_stacks.push(stack);
+        }
+        Ok(map)
+    }
+
+    /// Serializes the map in the Source Map revision 3 format, with `file` naming the output.
+    /// Each mapped line gets a single segment at its first column.
+    pub fn to_source_map_v3(&self, file: &str) -> String {
+        let sources = self.sources();
+        let mut mappings = String::new();
+        // the fields of a segment are relative to the previous segment
+        let (mut prev_source, mut prev_line) = (0, 0);
+
+        for (i, location) in self.lines.iter().enumerate() {
+            if i > 0 {
+                mappings.push(';');
+            }
+            if let Some(Location { file, line }) = location {
+                let source = sources.iter().position(|s| s == file).unwrap() as i64;
+                let line = *line as i64 - 1;
+                for value in [0, source - prev_source, line - prev_line, 0] {
+                    encode_vlq(value, &mut mappings);
+                }
+                (prev_source, prev_line) = (source, line);
+            }
+        }
+
+        let sources: Vec<_> = sources.iter().map(|s| json::string(s)).collect();
+        format!(
+            "{{\"version\":3,\"file\":{},\"sources\":[{}],\"names\":[],\"mappings\":{}}}",
+            json::string(file),
+            sources.join(","),
+            json::string(&mappings),
+        )
+    }
+}
+
+/// Appends `value` as a base64 variable length quantity
+fn encode_vlq(value: i64, out: &mut String) {
+    let mut vlq = if value < 0 { ((-value) << 1) | 1 } else { value << 1 };
+    loop {
+        let mut digit = vlq & 0b11111;
+        vlq >>= 5;
+        if vlq > 0 {
+            digit |= 0b100000;
+        }
+        out.push(BASE64[digit as usize] as char);
+        if vlq == 0 {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let map = SourceMap {
+            lines: vec![
+                Some(Location::new("main.js", 1)),
+                Some(Location::new("lib.js", 1)),
+                Some(Location::new("lib.js", 2)),
+                None,
+                Some(Location::new("main.js", 20)),
+            ],
+            line_stacks: vec![0, 1, 1, 0, 0],
+            include_stacks: vec![vec![], vec![Location::new("main.js", 2)]],
+        };
+
+        assert_eq!(map.get(2), Some(&Location::new("lib.js", 1)));
+        assert_eq!(map.get(4), None);
+        assert_eq!(map.get(0), None);
+        assert_eq!(map.locate(3), Some(Origin { location: &Location::new("lib.js", 2), include_stack: &[Location::new("main.js", 2)] }));
+        assert_eq!(map.locate(5).unwrap().include_stack, &[]);
+        assert_eq!(
+            map.to_json(),
+            "{\"sources\":[\"main.js\",\"lib.js\"],\"lines\":[[0,1,0],[1,1,1],[1,2,1],null,[0,20,0]],\"stacks\":[[],[[0,2]]]}",
+        );
+        assert_eq!(SourceMap::from_json(&map.to_json()), Ok(map.clone()));
+        assert_eq!(
+            map.to_source_map_v3("out.js"),
+            "{\"version\":3,\"file\":\"out.js\",\"sources\":[\"main.js\",\"lib.js\"],\"names\":[],\"mappings\":\"AAAA;ACAA;AACA;;ADkBA\"}",
+        );
+    }
+}
@@ -0,0 +1,285 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Table of defined symbols, consulted when evaluating conditional directives and substituted
+/// into emitted lines
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    defined: HashMap<String, String>,
+    /// arguments of the includes being expanded, they shadow definitions of the same name
+    arguments: HashMap<String, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    pub fn define(&mut self, name: &str, value: &str) {
+        self.defined.insert(name.to_owned(), value.to_owned());
+    }
+
+    pub fn undefine(&mut self, name: &str) -> Option<String> {
+        self.defined.remove(name)
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.arguments.contains_key(name) || self.defined.contains_key(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.arguments.get(name).or_else(|| self.defined.get(name)).map(|v| v.as_str())
+    }
+
+    /// Binds the arguments of an include while it's expanded, returning the arguments to restore
+    /// with [`Symbols::unbind_arguments`] afterwards. Definitions are kept apart, so those made
+    /// by the included file stay visible after it.
+    pub(crate) fn bind_arguments(&mut self, args: &[(String, String)]) -> HashMap<String, String> {
+        let previous = self.arguments.clone();
+        self.arguments.extend(args.iter().cloned());
+        previous
+    }
+
+    pub(crate) fn unbind_arguments(&mut self, previous: HashMap<String, String>) {
+        self.arguments = previous;
+    }
+
+    /// Substitutes defined symbols in `line`, both as bare `NAME` tokens and as `${NAME}`.
+    /// Include arguments are only substituted as `${NAME}`, so that the name may appear as text.
+    ///
+    /// Substitution is a single pass, the substituted values are not rescanned.
+    pub fn expand<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        if self.defined.is_empty() && self.arguments.is_empty() {
+            return Cow::Borrowed(line);
+        }
+
+        let mut expanded = String::new();
+        let mut copied = 0;
+        let mut pos = 0;
+
+        while pos < line.len() {
+            let rem = &line[pos..];
+            let word_len = rem.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rem.len());
+
+            let (len, value) = if word_len > 0 {
+                // numbers are never names, e.g. the `e2` of `1e2`
+                let value = is_identifier(&rem[..word_len]).then(|| self.defined.get(&rem[..word_len])).flatten().map(String::as_str);
+                (word_len, value)
+            } else if let Some(end) = rem.strip_prefix("${").and_then(|r| r.find('}')) {
+                (end + 3, self.get(&rem[2..end + 2]))
+            } else {
+                (rem.chars().next().map_or(1, char::len_utf8), None)
+            };
+
+            if let Some(value) = value {
+                expanded.push_str(&line[copied..pos]);
+                expanded.push_str(value);
+                copied = pos + len;
+            }
+            pos += len;
+        }
+
+        if copied == 0 {
+            Cow::Borrowed(line)
+        } else {
+            expanded.push_str(&line[copied..]);
+            Cow::Owned(expanded)
+        }
+    }
+
+    /// Evaluates the expression of an `&if` directive.
+    ///
+    /// Supports integers, quoted strings, symbol names, `defined(NAME)`, `!`, `==`, `!=`, `&&`, `||`
+    /// and parentheses. Undefined symbols evaluate to `0`, like in the C preprocessor.
+    pub fn evaluate(&self, expr: &str) -> Result<bool, String> {
+        let tokens = tokenize(expr)?;
+        let mut eval = Evaluator { tokens: &tokens, pos: 0, symbols: self };
+        let value = eval.or()?;
+        match eval.tokens.get(eval.pos) {
+            None => Ok(is_true(&value)),
+            Some(t) => Err(format!("unexpected `{}` in expression `{}`", t, expr)),
+        }
+    }
+}
+
+fn is_true(value: &str) -> bool {
+    match value.parse::<i64>() {
+        Ok(n) => n != 0,
+        Err(_) => !value.is_empty(),
+    }
+}
+
+/// Whether `s` can be used as a symbol name
+pub fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn tokenize(expr: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        } else if c.is_alphanumeric() || c == '_' {
+            let mut end = i + c.len_utf8();
+            while let Some((j, d)) = chars.next_if(|(_, d)| d.is_alphanumeric() || *d == '_') {
+                end = j + d.len_utf8();
+            }
+            tokens.push(expr[i..end].to_owned());
+        } else if c == '"' {
+            let end = expr[i + 1..].find('"').ok_or(format!("unterminated string in expression `{}`", expr))?;
+            tokens.push(expr[i..i + end + 2].to_owned());
+            while chars.next_if(|(j, _)| *j <= i + end + 1).is_some() {}
+        } else {
+            let two = expr.get(i..i + 2).unwrap_or("");
+            if ["&&", "||", "==", "!="].contains(&two) {
+                chars.next();
+                tokens.push(two.to_owned());
+            } else if "()!".contains(c) {
+                tokens.push(c.to_string());
+            } else {
+                return Err(format!("unexpected character `{}` in expression `{}`", c, expr));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Evaluator<'a> {
+    tokens: &'a [String],
+    pos: usize,
+    symbols: &'a Symbols,
+}
+
+impl Evaluator<'_> {
+    fn eat(&mut self, token: &str) -> bool {
+        if self.tokens.get(self.pos).is_some_and(|t| t == token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next(&mut self) -> Result<&str, String> {
+        let token = self.tokens.get(self.pos).ok_or("unexpected end of expression")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn or(&mut self) -> Result<String, String> {
+        let mut value = self.and()?;
+        while self.eat("||") {
+            let rhs = self.and()?;
+            value = bool_str(is_true(&value) || is_true(&rhs));
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<String, String> {
+        let mut value = self.comparison()?;
+        while self.eat("&&") {
+            let rhs = self.comparison()?;
+            value = bool_str(is_true(&value) && is_true(&rhs));
+        }
+        Ok(value)
+    }
+
+    fn comparison(&mut self) -> Result<String, String> {
+        let mut value = self.unary()?;
+        loop {
+            let equal = if self.eat("==") {
+                true
+            } else if self.eat("!=") {
+                false
+            } else {
+                return Ok(value);
+            };
+            let rhs = self.unary()?;
+            let same = match (value.parse::<i64>(), rhs.parse::<i64>()) {
+                (Ok(l), Ok(r)) => l == r,
+                _ => value == rhs,
+            };
+            value = bool_str(same == equal);
+        }
+    }
+
+    fn unary(&mut self) -> Result<String, String> {
+        if self.eat("!") {
+            Ok(bool_str(!is_true(&self.unary()?)))
+        } else if self.eat("(") {
+            let value = self.or()?;
+            if self.eat(")") {
+                Ok(value)
+            } else {
+                Err("missing `)` in expression".into())
+            }
+        } else if self.eat("defined") {
+            let parens = self.eat("(");
+            let name = self.next()?.to_owned();
+            if !is_identifier(&name) {
+                return Err(format!("expected symbol name after `defined`, found `{}`", name));
+            }
+            if parens && !self.eat(")") {
+                return Err("missing `)` after `defined`".into());
+            }
+            Ok(bool_str(self.symbols.is_defined(&name)))
+        } else {
+            let symbols = self.symbols;
+            let token = self.next()?;
+            if let Some(s) = token.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+                Ok(s.to_owned())
+            } else if is_identifier(token) {
+                Ok(symbols.get(token).unwrap_or("0").to_owned())
+            } else if token.parse::<i64>().is_ok() {
+                Ok(token.to_owned())
+            } else {
+                Err(format!("unexpected `{}` in expression", token))
+            }
+        }
+    }
+}
+
+fn bool_str(b: bool) -> String {
+    if b { "1" } else { "0" }.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluate_expressions() {
+        let mut symbols = Symbols::new();
+        symbols.define("DEBUG", "1");
+        symbols.define("PLATFORM", "linux");
+
+        assert!(symbols.evaluate("1").unwrap());
+        assert!(!symbols.evaluate("0").unwrap());
+        assert!(symbols.evaluate("DEBUG").unwrap());
+        assert!(!symbols.evaluate("RELEASE").unwrap());
+        assert!(symbols.evaluate("defined(DEBUG) && !defined RELEASE").unwrap());
+        assert!(symbols.evaluate("PLATFORM == \"linux\" || PLATFORM == \"macos\"").unwrap());
+        assert!(symbols.evaluate("!(DEBUG != 1)").unwrap());
+
+        assert!(symbols.evaluate("DEBUG &&").is_err());
+        assert!(symbols.evaluate("(DEBUG").is_err());
+        assert!(symbols.evaluate("DEBUG + 1").is_err());
+    }
+
+    #[test]
+    fn expand_symbols() {
+        let mut symbols = Symbols::new();
+        symbols.define("VERSION", "1.4.2");
+        symbols.define("NAME", "preproc");
+
+        assert_eq!(symbols.expand("NAME VERSION"), "preproc 1.4.2");
+        assert_eq!(symbols.expand("v${VERSION}-${NAME}.tar"), "v1.4.2-preproc.tar");
+        assert_eq!(symbols.expand("VERSIONS NAME_ 2VERSION ${OTHER}"), "VERSIONS NAME_ 2VERSION ${OTHER}");
+        assert!(matches!(symbols.expand("nothing to see"), Cow::Borrowed(_)));
+    }
+}